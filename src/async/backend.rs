@@ -0,0 +1,236 @@
+/*
+ * This file is part of Tokio ZMQ.
+ *
+ * Copyright © 2017 Riley Trautman
+ *
+ * Tokio ZMQ is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Tokio ZMQ is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Tokio ZMQ.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Defines `SocketBackend`, the trait `MultipartStream`/`MultipartSink` are generic over.
+//! Everything those types do on top of it -- buffering, the
+//! `ControlledStream`/`EndingStream`/`TimeoutStream` combinators, `MultipartSinkStream` -- is
+//! written once and works for any backend, such as the default reactor-driven `ReactorBackend`
+//! here or `async::threaded::ThreadedSocket`.
+//!
+//! This is deliberately a different axis from `MultipartRequest<T>`/`MultipartResponse<T>`'s `T:
+//! From<(zmq::Socket, PollEvented2<File<ZmqFile>>)>` parameter, which picks which socket wrapper
+//! type a one-shot send/recv resolves back into; that conversion is tied to the raw reactor FD
+//! pair and isn't meaningful for a backend like `ThreadedSocket`, which never hands the socket
+//! back out.
+//!
+//! `SocketBackend` is `pub`, and so is this module, precisely so a third party can ship a backend
+//! of their own -- e.g. one built on a different executor's reactor -- without needing anything
+//! from this crate beyond the trait itself; `ReactorBackend` and `ThreadedSocket` are just the two
+//! this crate ships out of the box, not an exhaustive list.
+//!
+//! `SocketBackend` implementors hold no self-referential state, so `MultipartStream`/
+//! `MultipartSink` can store one directly (no `Polling` placeholder, no `Pin` gymnastics beyond
+//! the blanket `Unpin` every such backend gets for free).
+
+use std::task::{Context, Poll};
+
+use mio::Ready;
+use zmq;
+use tokio::reactor::PollEvented2;
+use tokio_file_unix::File;
+
+use error::Error;
+use file::ZmqFile;
+use message::Multipart;
+
+/// A way of getting `Multipart`s on and off a socket without blocking the calling task.
+///
+/// Implementors track at most one in-flight receive and one in-flight send at a time.
+pub trait SocketBackend: Unpin {
+    /// Poll for the next incoming `Multipart`.
+    fn poll_recv(&mut self, cx: &mut Context) -> Poll<Result<Multipart, Error>>;
+
+    /// Start sending `multipart`. Only call this when no send is already in flight, i.e. when
+    /// `is_sending` reports `false`.
+    fn start_send(&mut self, multipart: Multipart) -> Result<(), Error>;
+
+    /// Poll for the completion of a `send` started via `start_send`.
+    fn poll_send(&mut self, cx: &mut Context) -> Poll<Result<(), Error>>;
+
+    /// Whether a `send` started via `start_send` hasn't finished yet. Callers built on top of a
+    /// `SocketBackend` (`MultipartSink`, `MultipartSinkStream`) need this to tell "idle, ready for
+    /// the next `start_send`" apart from "mid-send" -- calling `start_send` again while this is
+    /// `true` discards whatever hadn't gone out yet.
+    fn is_sending(&self) -> bool;
+}
+
+/// The default `SocketBackend`, driving a `zmq::Socket` off its file descriptor through a
+/// reactor-registered `PollEvented2`.
+pub struct ReactorBackend {
+    sock: zmq::Socket,
+    file: PollEvented2<File<ZmqFile>>,
+    recv_partial: Option<Multipart>,
+    send_remaining: Option<Multipart>,
+}
+
+impl ReactorBackend {
+    pub fn new(sock: zmq::Socket, file: PollEvented2<File<ZmqFile>>) -> Self {
+        ReactorBackend {
+            sock,
+            file,
+            recv_partial: None,
+            send_remaining: None,
+        }
+    }
+
+    /// Split back into the raw socket and file, e.g. to hand off to another wrapper type.
+    pub fn into_raw(self) -> (zmq::Socket, PollEvented2<File<ZmqFile>>) {
+        (self.sock, self.file)
+    }
+
+    /// Borrow the raw socket this backend drives, e.g. to change a sockopt at runtime without
+    /// tearing down the stream/sink built on top of it.
+    pub(crate) fn as_raw_socket(&self) -> &zmq::Socket {
+        &self.sock
+    }
+
+    fn poll_read_ready(&mut self, cx: &mut Context) -> Result<bool, Error> {
+        if let Poll::Pending = self.file.poll_read_ready2(cx, Ready::readable())? {
+            let events = self.sock.get_events()? as i16;
+
+            if events & zmq::POLLIN != 0 {
+                self.file.clear_read_ready2(cx, Ready::readable())?;
+                cx.waker().wake_by_ref();
+            } else {
+                self.file.clear_read_ready2(cx, Ready::readable())?;
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn poll_write_ready(&mut self, cx: &mut Context) -> Result<bool, Error> {
+        if let Poll::Pending = self.file.poll_write_ready2(cx)? {
+            let events = self.sock.get_events()? as i16;
+
+            if events & zmq::POLLOUT != 0 {
+                self.file.clear_write_ready2(cx)?;
+                cx.waker().wake_by_ref();
+            } else {
+                self.file.clear_write_ready2(cx)?;
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl SocketBackend for ReactorBackend {
+    fn poll_recv(&mut self, cx: &mut Context) -> Poll<Result<Multipart, Error>> {
+        match self.poll_read_ready(cx) {
+            Ok(true) => (),
+            Ok(false) => return Poll::Pending,
+            Err(e) => return Poll::Ready(Err(e)),
+        }
+
+        let mut first = true;
+
+        loop {
+            let mut msg = match zmq::Message::new() {
+                Ok(msg) => msg,
+                Err(e) => return Poll::Ready(Err(e.into())),
+            };
+
+            match self.sock.recv(&mut msg, zmq::DONTWAIT) {
+                Ok(_) => {
+                    first = false;
+
+                    let more = msg.get_more();
+                    let mut multipart = self.recv_partial.take().unwrap_or_default();
+                    multipart.push_back(msg);
+
+                    if !more {
+                        return Poll::Ready(Ok(multipart));
+                    }
+
+                    self.recv_partial = Some(multipart);
+                }
+                Err(zmq::Error::EAGAIN) => {
+                    if first {
+                        if let Err(e) = self.file.clear_read_ready2(cx, Ready::readable()) {
+                            return Poll::Ready(Err(e.into()));
+                        }
+                        return Poll::Pending;
+                    }
+                }
+                Err(e) => return Poll::Ready(Err(e.into())),
+            }
+        }
+    }
+
+    fn start_send(&mut self, multipart: Multipart) -> Result<(), Error> {
+        self.send_remaining = Some(multipart);
+
+        Ok(())
+    }
+
+    fn poll_send(&mut self, cx: &mut Context) -> Poll<Result<(), Error>> {
+        match self.poll_write_ready(cx) {
+            Ok(true) => (),
+            Ok(false) => return Poll::Pending,
+            Err(e) => return Poll::Ready(Err(e)),
+        }
+
+        while let Some(mut multipart) = self.send_remaining.take() {
+            let msg = match multipart.pop_front() {
+                Some(msg) => msg,
+                None => break,
+            };
+
+            let events = match self.sock.get_events() {
+                Ok(events) => events as i16,
+                Err(e) => return Poll::Ready(Err(e.into())),
+            };
+
+            if events & zmq::POLLOUT == 0 {
+                if let Err(e) = self.file.clear_write_ready2(cx) {
+                    return Poll::Ready(Err(e.into()));
+                }
+                cx.waker().wake_by_ref();
+
+                multipart.push_front(msg);
+                self.send_remaining = Some(multipart);
+
+                return Poll::Pending;
+            }
+
+            let flags = zmq::DONTWAIT | if multipart.is_empty() {
+                0
+            } else {
+                zmq::SNDMORE
+            };
+
+            if let Err(e) = self.sock.send_msg(msg, flags) {
+                // The frame that just failed isn't recoverable -- zmq::Socket::send_msg doesn't
+                // hand the Message back on error -- but whatever hadn't been sent yet still is.
+                return Poll::Ready(Err(Error::MultipartSend(multipart, e)));
+            }
+
+            self.send_remaining = Some(multipart);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn is_sending(&self) -> bool {
+        self.send_remaining.is_some()
+    }
+}
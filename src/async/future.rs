@@ -18,15 +18,19 @@
  */
 
 //! This module contains definitions for `MultipartRequest` and `MultipartResponse`, the two types that
-//! implement `futures::Future`.
+//! implement `std::future::Future`.
 
+use std::collections::VecDeque;
+use std::future::Future;
 use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-use futures::{Async, Future};
-use futures::task::Context;
 use mio::Ready;
 use tokio::reactor::PollEvented2;
 use tokio_file_unix::File;
+use tokio_timer::{Sleep, Timer};
 use zmq;
 
 use error::Error;
@@ -39,17 +43,16 @@ use message::Multipart;
 /// You shouldn't ever need to manually create one, but if you do, the following will suffice.
 /// ### Example
 /// ```rust
-/// # #![feature(conservative_impl_trait)]
 /// # #![feature(try_from)]
 /// #
 /// # extern crate zmq;
-/// # extern crate futures;
+/// # extern crate futures_util;
 /// # extern crate tokio_zmq;
 /// #
 /// # use std::convert::TryInto;
 /// # use std::sync::Arc;
 /// #
-/// # use futures::{Future, FutureExt};
+/// # use futures_util::FutureExt;
 /// # use tokio_zmq::prelude::*;
 /// # use tokio_zmq::async::MultipartRequest;
 /// # use tokio_zmq::{Error, Rep, Socket};
@@ -57,7 +60,7 @@ use message::Multipart;
 /// # fn main() {
 /// #     get_sock();
 /// # }
-/// # fn get_sock() -> impl Future<Item = (), Error = Error> {
+/// # fn get_sock() -> impl std::future::Future<Output = Result<Rep, Error>> {
 /// #     let ctx = Arc::new(zmq::Context::new());
 /// #     let rep: Rep = Socket::builder(ctx)
 /// #         .bind("tcp://*:5567")
@@ -66,35 +69,85 @@ use message::Multipart;
 /// #     let socket = rep.socket();
 /// #     let (sock, file) = socket.inner();
 /// #     let msg = zmq::Message::from_slice(format!("Hey").as_bytes()).unwrap();
-/// MultipartRequest::new(sock, file, msg.into()).and_then(|(_, _)| {
-///     // succesfull request
-///     # Ok(())
-/// })
+/// MultipartRequest::new(sock, file, msg.into())
 /// # }
 /// ```
-pub struct MultipartRequest<T>
+pub struct MultipartRequest<T, S = zmq::Message>
 where
     T: From<(zmq::Socket, PollEvented2<File<ZmqFile>>)>,
+    S: Into<zmq::Message>,
 {
     sock: Option<zmq::Socket>,
     file: Option<PollEvented2<File<ZmqFile>>>,
-    multipart: Option<Multipart>,
+    pending: Option<VecDeque<S>>,
+    // A frame that was already converted into a zmq::Message before hitting EAGAIN; retried as-is
+    // rather than re-running the S -> zmq::Message conversion.
+    retry: Option<zmq::Message>,
+    // Armed only by `with_timeout`/`from_frames_with_timeout`; fires at most once.
+    timeout: Option<Sleep>,
     phantom: PhantomData<T>,
 }
 
-impl<T> MultipartRequest<T>
+impl<T> MultipartRequest<T, zmq::Message>
 where
     T: From<(zmq::Socket, PollEvented2<File<ZmqFile>>)>,
 {
     pub fn new(sock: zmq::Socket, file: PollEvented2<File<ZmqFile>>, multipart: Multipart) -> Self {
+        MultipartRequest::from_frames(sock, file, multipart)
+    }
+
+    /// Like `new`, but fails with `Error::Timeout` if the send doesn't finish within `duration`.
+    pub fn with_timeout(
+        sock: zmq::Socket,
+        file: PollEvented2<File<ZmqFile>>,
+        multipart: Multipart,
+        duration: Duration,
+    ) -> Self {
+        MultipartRequest::from_frames_with_timeout(sock, file, multipart, duration)
+    }
+}
+
+impl<T, S> MultipartRequest<T, S>
+where
+    T: From<(zmq::Socket, PollEvented2<File<ZmqFile>>)>,
+    S: Into<zmq::Message>,
+{
+    /// Build a `MultipartRequest` out of anything convertible into a `zmq::Message` -- a
+    /// `zmq::Message` itself, a `&[u8]`, a `Vec<u8>`, etc. -- without converting any frame until
+    /// it's actually handed to `zmq_send`. This is what lets a caller that already owns a `Vec<u8>`
+    /// avoid the copy `zmq::Message::from_slice` would otherwise force up front.
+    pub fn from_frames<I>(sock: zmq::Socket, file: PollEvented2<File<ZmqFile>>, frames: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+    {
         MultipartRequest {
             sock: Some(sock),
             file: Some(file),
-            multipart: Some(multipart),
+            pending: Some(frames.into_iter().collect()),
+            retry: None,
+            timeout: None,
             phantom: PhantomData,
         }
     }
 
+    /// Like `from_frames`, but fails with `Error::Timeout` if the send doesn't finish within
+    /// `duration`. On timeout, the underlying socket is released the same way any other error
+    /// does -- it's not converted into `T`, since a `Rep`/`Req` wrapper should only ever be
+    /// rebuilt around a socket that's known to be in a good state.
+    pub fn from_frames_with_timeout<I>(
+        sock: zmq::Socket,
+        file: PollEvented2<File<ZmqFile>>,
+        frames: I,
+        duration: Duration,
+    ) -> Self
+    where
+        I: IntoIterator<Item = S>,
+    {
+        let mut this = MultipartRequest::from_frames(sock, file, frames);
+        this.timeout = Some(Timer::default().sleep(duration));
+        this
+    }
+
     pub(crate) fn take_socket(&mut self) -> Option<(zmq::Socket, PollEvented2<File<ZmqFile>>)> {
         if self.sock.is_some() && self.file.is_some() {
             self.sock
@@ -110,43 +163,61 @@ where
         self.file = Some(file);
     }
 
-    fn send(&mut self, cx: &mut Context) -> Result<Async<()>, Error> {
-        while let Some(mut multipart) = self.multipart.take() {
-            let msg = match multipart.pop_front() {
-                Some(msg) => msg,
+    fn send(&mut self, cx: &mut Context) -> Result<Poll<()>, Error> {
+        loop {
+            if let Some(msg) = self.retry.take() {
+                let last = self.pending.as_ref().map(|p| p.is_empty()).unwrap_or(true);
+                let place = if last { MsgPlace::Last } else { MsgPlace::Nth };
+
+                debug!("MultipartRequest: retrying: {:?}", msg.as_str());
+                match self.send_msg(msg, &place, cx)? {
+                    Some(msg) => {
+                        self.retry = Some(msg);
+                        return Ok(Poll::Pending);
+                    }
+                    None => {
+                        if last {
+                            self.pending = None;
+                            return Ok(Poll::Ready(()));
+                        }
+                    }
+                }
+            }
+
+            let mut pending = match self.pending.take() {
+                Some(pending) => pending,
+                None => return Ok(Poll::Ready(())),
+            };
+
+            let frame = match pending.pop_front() {
+                Some(frame) => frame,
                 None => {
-                    self.multipart = None;
                     self.file
                         .as_ref()
                         .ok_or(Error::Reused)?
                         .clear_write_ready2(cx)?;
-                    cx.waker().wake();
-                    break;
+                    cx.waker().wake_by_ref();
+                    return Ok(Poll::Ready(()));
                 }
             };
 
-            let place = if multipart.is_empty() {
-                MsgPlace::Last
-            } else {
-                MsgPlace::Nth
-            };
+            let last = pending.is_empty();
+            let place = if last { MsgPlace::Last } else { MsgPlace::Nth };
+            self.pending = Some(pending);
 
-            debug!("MultipartRequest: sending: {:?}", msg.as_str());
-            match self.send_msg(msg, &place, cx)? {
+            match self.send_msg(frame.into(), &place, cx)? {
+                Some(msg) => {
+                    self.retry = Some(msg);
+                    return Ok(Poll::Pending);
+                }
                 None => {
-                    if multipart.is_empty() {
-                        break;
+                    if last {
+                        self.pending = None;
+                        return Ok(Poll::Ready(()));
                     }
                 }
-                Some(msg) => {
-                    multipart.push_front(msg);
-                }
             }
-
-            self.multipart = Some(multipart);
         }
-
-        Ok(Async::Ready(()))
     }
 
     fn send_msg(
@@ -163,7 +234,7 @@ where
                 .ok_or(Error::Reused)?
                 .clear_write_ready2(cx)?;
 
-            cx.waker().wake();
+            cx.waker().wake_by_ref();
 
             return Ok(Some(msg));
         }
@@ -180,17 +251,27 @@ where
             .send_msg(msg, flags)
         {
             Ok(_) => Ok(None),
-            Err(e @ zmq::Error::EAGAIN) => {
-                // return message in future
+            Err(zmq::Error::EAGAIN) => {
+                // The socket reported POLLOUT but zmq_send still returned EAGAIN (e.g. another
+                // task raced us). Re-queue the frame, clear write-readiness, and ask to be polled
+                // again rather than losing it or surfacing a spurious error mid-multipart.
                 debug!("MultipartRequest: EAGAIN");
-                Err(e.into())
+
+                self.file
+                    .as_ref()
+                    .ok_or(Error::Reused)?
+                    .clear_write_ready2(cx)?;
+
+                cx.waker().wake_by_ref();
+
+                Ok(Some(msg))
             }
             Err(e) => Err(e.into()),
         }
     }
 
     fn check_write(&mut self, cx: &mut Context) -> Result<bool, Error> {
-        if let Async::Pending = self.file
+        if let Poll::Pending = self.file
             .as_ref()
             .ok_or(Error::Reused)?
             .poll_write_ready2(cx)?
@@ -203,7 +284,7 @@ where
                     .as_ref()
                     .ok_or(Error::Reused)?
                     .clear_write_ready2(cx)?;
-                cx.waker().wake();
+                cx.waker().wake_by_ref();
             } else {
                 self.file
                     .as_ref()
@@ -217,28 +298,48 @@ where
     }
 }
 
-impl<T> Future for MultipartRequest<T>
+impl<T, S> Future for MultipartRequest<T, S>
 where
     T: From<(zmq::Socket, PollEvented2<File<ZmqFile>>)>,
+    S: Into<zmq::Message>,
 {
-    type Item = T;
-    type Error = Error;
-
-    fn poll(&mut self, cx: &mut Context) -> Result<Async<Self::Item>, Self::Error> {
-        if self.check_write(cx)? {
-            self.send(cx).and_then(|async| {
-                Ok(match async {
-                    Async::Ready(_) => {
-                        let sock = self.sock.take().ok_or(Error::Reused)?;
-                        let file = self.file.take().ok_or(Error::Reused)?;
-
-                        Async::Ready((sock, file).into())
-                    }
-                    _ => Async::Pending,
-                })
-            })
-        } else {
-            Ok(Async::Pending)
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        if let Some(timeout) = this.timeout.as_mut() {
+            match Pin::new(timeout).poll(cx) {
+                Poll::Ready(Ok(())) => {
+                    this.take_socket();
+                    return Poll::Ready(Err(Error::Timeout));
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                Poll::Pending => (),
+            }
+        }
+
+        match this.check_write(cx) {
+            Ok(true) => (),
+            Ok(false) => return Poll::Pending,
+            Err(e) => return Poll::Ready(Err(e)),
+        }
+
+        match this.send(cx) {
+            Ok(Poll::Ready(())) => {
+                let sock = match this.sock.take().ok_or(Error::Reused) {
+                    Ok(sock) => sock,
+                    Err(e) => return Poll::Ready(Err(e)),
+                };
+                let file = match this.file.take().ok_or(Error::Reused) {
+                    Ok(file) => file,
+                    Err(e) => return Poll::Ready(Err(e)),
+                };
+
+                Poll::Ready(Ok((sock, file).into()))
+            }
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
         }
     }
 }
@@ -248,17 +349,16 @@ where
 /// You shouldn't ever need to manually create one, but if you do, the following will suffice.
 /// ### Example
 /// ```rust
-/// # #![feature(conservative_impl_trait)]
 /// # #![feature(try_from)]
 /// #
 /// # extern crate zmq;
-/// # extern crate futures;
+/// # extern crate futures_util;
 /// # extern crate tokio_zmq;
 /// #
 /// # use std::convert::TryInto;
 /// # use std::sync::Arc;
 /// #
-/// # use futures::{Future, FutureExt};
+/// # use futures_util::FutureExt;
 /// # use tokio_zmq::prelude::*;
 /// # use tokio_zmq::async::{MultipartResponse};
 /// # use tokio_zmq::{Error, Multipart, Rep, Socket};
@@ -266,7 +366,7 @@ where
 /// # fn main() {
 /// #     get_sock();
 /// # }
-/// # fn get_sock() -> impl Future<Item = Multipart, Error = Error> {
+/// # fn get_sock() -> impl std::future::Future<Output = Result<(Multipart, Rep), Error>> {
 /// #     let ctx = Arc::new(zmq::Context::new());
 /// #     let rep: Rep = Socket::builder(ctx)
 /// #         .bind("tcp://*:5567")
@@ -274,10 +374,7 @@ where
 /// #         .unwrap();
 /// #     let socket = rep.socket();
 /// #     let (sock, file) = socket.inner();
-/// MultipartResponse::new(sock, file).and_then(|(multipart, (_, _))| {
-///     // handle multipart response
-///     # Ok(multipart)
-/// })
+/// MultipartResponse::new(sock, file)
 /// # }
 /// ```
 pub struct MultipartResponse<T>
@@ -287,6 +384,8 @@ where
     sock: Option<zmq::Socket>,
     file: Option<PollEvented2<File<ZmqFile>>>,
     multipart: Option<Multipart>,
+    // Armed only by `with_timeout`; fires at most once, and only before the first frame arrives.
+    timeout: Option<Sleep>,
     phantom: PhantomData<T>,
 }
 
@@ -299,10 +398,24 @@ where
             sock: Some(sock),
             file: Some(file),
             multipart: None,
+            timeout: None,
             phantom: PhantomData,
         }
     }
 
+    /// Like `new`, but fails with `Error::Timeout` if no frame arrives within `duration`. Once the
+    /// first frame of the multipart has arrived, the timeout stops applying -- aborting a
+    /// partially-received multipart would desync the socket's framing for whoever reads it next.
+    pub fn with_timeout(
+        sock: zmq::Socket,
+        file: PollEvented2<File<ZmqFile>>,
+        duration: Duration,
+    ) -> Self {
+        let mut this = MultipartResponse::new(sock, file);
+        this.timeout = Some(Timer::default().sleep(duration));
+        this
+    }
+
     pub(crate) fn take_socket(&mut self) -> Option<(zmq::Socket, PollEvented2<File<ZmqFile>>)> {
         if self.sock.is_some() && self.file.is_some() {
             self.sock
@@ -318,7 +431,7 @@ where
         self.file = Some(file);
     }
 
-    fn recv(&mut self, cx: &mut Context) -> Result<Async<Multipart>, Error> {
+    fn recv(&mut self, cx: &mut Context) -> Result<Poll<Multipart>, Error> {
         let events = self.sock.as_ref().ok_or(Error::Reused)?.get_events()? as i16;
 
         if events & zmq::POLLIN == 0 {
@@ -327,16 +440,16 @@ where
                 .ok_or(Error::Reused)?
                 .clear_read_ready2(cx, Ready::readable())?;
 
-            cx.waker().wake();
+            cx.waker().wake_by_ref();
 
-            return Ok(Async::Pending);
+            return Ok(Poll::Pending);
         }
 
         let mut first = true;
 
         loop {
             match self.recv_msg()? {
-                Async::Ready(msg) => {
+                Poll::Ready(msg) => {
                     first = false;
                     let mut multipart = self.multipart.take().unwrap_or_default();
 
@@ -345,21 +458,21 @@ where
                     multipart.push_back(msg);
 
                     if !more {
-                        return Ok(Async::Ready(multipart));
+                        return Ok(Poll::Ready(multipart));
                     }
 
                     self.multipart = Some(multipart);
                 }
-                Async::Pending => {
+                Poll::Pending => {
                     if first {
-                        return Ok(Async::Pending);
+                        return Ok(Poll::Pending);
                     }
                 }
             }
         }
     }
 
-    fn recv_msg(&mut self) -> Result<Async<zmq::Message>, Error> {
+    fn recv_msg(&mut self) -> Result<Poll<zmq::Message>, Error> {
         let mut msg = zmq::Message::new()?;
 
         match self.sock
@@ -369,18 +482,18 @@ where
         {
             Ok(_) => {
                 debug!("MultipartResponse: received: {:?}", msg.as_str());
-                Ok(Async::Ready(msg))
+                Ok(Poll::Ready(msg))
             }
             Err(zmq::Error::EAGAIN) => {
                 debug!("MultipartResponse: EAGAIN");
-                Ok(Async::Pending)
+                Ok(Poll::Pending)
             }
             Err(e) => Err(e.into()),
         }
     }
 
     fn check_read(&mut self, cx: &mut Context) -> Result<bool, Error> {
-        if let Async::Pending = self.file
+        if let Poll::Pending = self.file
             .as_ref()
             .ok_or(Error::Reused)?
             .poll_read_ready2(cx, Ready::readable())?
@@ -392,7 +505,7 @@ where
                     .as_ref()
                     .ok_or(Error::Reused)?
                     .clear_read_ready2(cx, Ready::readable())?;
-                cx.waker().wake();
+                cx.waker().wake_by_ref();
             } else {
                 self.file
                     .as_ref()
@@ -410,24 +523,45 @@ impl<T> Future for MultipartResponse<T>
 where
     T: From<(zmq::Socket, PollEvented2<File<ZmqFile>>)>,
 {
-    type Item = (Multipart, T);
-    type Error = Error;
-
-    fn poll(&mut self, cx: &mut Context) -> Result<Async<Self::Item>, Self::Error> {
-        if self.check_read(cx)? {
-            self.recv(cx).and_then(|async| {
-                Ok(match async {
-                    Async::Ready(multipart) => {
-                        let sock = self.sock.take().ok_or(Error::Reused)?;
-                        let file = self.file.take().ok_or(Error::Reused)?;
-
-                        Async::Ready((multipart, (sock, file).into()))
+    type Output = Result<(Multipart, T), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        if this.multipart.is_none() {
+            if let Some(timeout) = this.timeout.as_mut() {
+                match Pin::new(timeout).poll(cx) {
+                    Poll::Ready(Ok(())) => {
+                        this.take_socket();
+                        return Poll::Ready(Err(Error::Timeout));
                     }
-                    _ => Async::Pending,
-                })
-            })
-        } else {
-            Ok(Async::Pending)
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                    Poll::Pending => (),
+                }
+            }
+        }
+
+        match this.check_read(cx) {
+            Ok(true) => (),
+            Ok(false) => return Poll::Pending,
+            Err(e) => return Poll::Ready(Err(e)),
+        }
+
+        match this.recv(cx) {
+            Ok(Poll::Ready(multipart)) => {
+                let sock = match this.sock.take().ok_or(Error::Reused) {
+                    Ok(sock) => sock,
+                    Err(e) => return Poll::Ready(Err(e)),
+                };
+                let file = match this.file.take().ok_or(Error::Reused) {
+                    Ok(file) => file,
+                    Err(e) => return Poll::Ready(Err(e)),
+                };
+
+                Poll::Ready(Ok((multipart, (sock, file).into())))
+            }
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
         }
     }
 }
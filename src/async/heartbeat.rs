@@ -0,0 +1,128 @@
+/*
+ * This file is part of Tokio ZMQ.
+ *
+ * Copyright © 2017 Riley Trautman
+ *
+ * Tokio ZMQ is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Tokio ZMQ is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Tokio ZMQ.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! Defines `HeartbeatStream`, a ZMTP-style keepalive layer for connection-oriented sockets
+//! (`Dealer`, `Router`, `Req`, `Rep`) built on top of `TimeoutStream`.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::future::Either;
+use futures::{Sink, Stream};
+
+use async::stream::{Timeout, TimeoutStream};
+use error::Error;
+use message::Multipart;
+use prelude::HeartbeatHandler;
+
+/// Wraps a `Stream`/`Sink` pair with a ZMTP-style heartbeat.
+///
+/// Every `interval`, a ping (built by the `HeartbeatHandler`) is sent on the sink and a
+/// missed-beat counter ticks up. The underlying `TimeoutStream` fires on a fixed period, so other
+/// incoming traffic doesn't postpone the ping. Any incoming `Multipart` the handler recognizes as
+/// a pong resets that counter; once more than `max_missed_beats` intervals pass without one, the
+/// peer is considered dead and the stream ends, the same way an `EndHandler`-driven
+/// `EndingStream` would.
+///
+/// Traffic that isn't a pong is passed straight through to the consumer.
+pub struct HeartbeatStream<H, K, S>
+where
+    H: HeartbeatHandler,
+    K: Sink<Multipart, Error = Error> + Unpin,
+    S: Stream<Item = Result<Multipart, Error>> + Unpin,
+{
+    stream: TimeoutStream<S>,
+    sink: K,
+    handler: H,
+    missed_beats: usize,
+}
+
+impl<H, K, S> HeartbeatStream<H, K, S>
+where
+    H: HeartbeatHandler,
+    K: Sink<Multipart, Error = Error> + Unpin,
+    S: Stream<Item = Result<Multipart, Error>> + Unpin,
+{
+    /// Wrap `stream`/`sink` with a heartbeat that pings whenever `stream` is quiet for `interval`.
+    pub fn new(stream: S, sink: K, interval: Duration, handler: H) -> Self {
+        HeartbeatStream {
+            stream: TimeoutStream::new(stream, interval),
+            sink,
+            handler,
+            missed_beats: 0,
+        }
+    }
+}
+
+impl<H, K, S> Stream for HeartbeatStream<H, K, S>
+where
+    H: HeartbeatHandler,
+    K: Sink<Multipart, Error = Error> + Unpin,
+    S: Stream<Item = Result<Multipart, Error>> + Unpin,
+{
+    type Item = Result<Multipart, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            match Pin::new(&mut this.stream).poll_next(cx) {
+                Poll::Ready(Some(Ok(Either::Left(multipart)))) => {
+                    if this.handler.is_pong(&multipart) {
+                        this.missed_beats = 0;
+                        continue;
+                    }
+
+                    return Poll::Ready(Some(Ok(multipart)));
+                }
+                Poll::Ready(Some(Ok(Either::Right(Timeout)))) => {
+                    this.missed_beats += 1;
+
+                    if this.missed_beats > this.handler.max_missed_beats() {
+                        return Poll::Ready(None);
+                    }
+
+                    match Pin::new(&mut this.sink).poll_ready(cx) {
+                        Poll::Ready(Ok(())) => {
+                            let ping = this.handler.ping();
+
+                            if let Err(e) = Pin::new(&mut this.sink).start_send(ping) {
+                                return Poll::Ready(Some(Err(e)));
+                            }
+
+                            if let Poll::Ready(Err(e)) = Pin::new(&mut this.sink).poll_flush(cx) {
+                                return Poll::Ready(Some(Err(e)));
+                            }
+                        }
+                        Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                        // The previous ping hasn't drained yet; don't build a new one to
+                        // replace it. The interval already ticked, so loop back around --
+                        // `stream`'s next poll_next will report Pending once there's truly
+                        // nothing to do.
+                        Poll::Pending => continue,
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -22,13 +22,24 @@
 //! defines receiving data from a socket as an asychronous stream, and the `sink` module, which
 //! defines sending data to a socket as an asychronous sink.
 
+pub mod backend;
 pub mod future;
+pub mod heartbeat;
 pub mod sink;
+pub mod sink_stream;
 pub mod stream;
+#[cfg(feature = "threaded-backend")]
+pub(crate) mod threaded;
 
+pub use self::backend::{ReactorBackend, SocketBackend};
 pub use self::future::{MultipartRequest, MultipartResponse};
+pub use self::heartbeat::HeartbeatStream;
 pub use self::sink::MultipartSink;
-pub use self::stream::{ControlledStream, MultipartStream};
+pub use self::sink_stream::MultipartSinkStream;
+pub use self::stream::{ControlledStream, EndingStream, MultipartStream, SignalControlledStream,
+                        Timeout, ThrottlingStream, TimeoutStream};
+#[cfg(feature = "threaded-backend")]
+pub use self::threaded::{ThreadedRequest, ThreadedResponse, ThreadedSocket};
 
 /// This type is used to determine what flags should be used when sending messages. If a message is
 /// the last in it's `Multipart`, it should not have the SNDMORE flag set.
@@ -20,16 +20,19 @@
 //! This module defines the `MultipartSink` type. A wrapper around Sockets that implements
 //! `futures::Sink`.
 
-use std::mem::swap;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
 use zmq;
 use tokio::reactor::PollEvented2;
-use futures::task::Context;
 use tokio_file_unix::File;
-use futures::{Async, Future, Sink};
+use futures::Sink;
 
 use message::Multipart;
-use async::future::MultipartRequest;
+use async::backend::{ReactorBackend, SocketBackend};
+#[cfg(feature = "threaded-backend")]
+use async::threaded::ThreadedSocket;
 use error::Error;
 use file::ZmqFile;
 
@@ -43,16 +46,18 @@ use file::ZmqFile;
 /// #![feature(conservative_impl_trait)]
 ///
 /// extern crate zmq;
-/// extern crate futures;
+/// extern crate futures_util;
+/// extern crate futures_core;
 /// extern crate tokio;
 /// extern crate tokio_zmq;
 ///
 /// use std::sync::Arc;
 ///
-/// use futures::{FutureExt, Sink, SinkExt};
+/// use futures_util::SinkExt;
+/// use futures_core::Sink;
 /// use tokio_zmq::{Error, Multipart, Socket};
 ///
-/// fn get_sink(socket: Socket) -> impl Sink<SinkItem = Multipart, SinkError = Error> {
+/// fn get_sink(socket: Socket) -> impl Sink<Multipart, Error = Error> {
 ///     socket.sink()
 /// }
 ///
@@ -69,109 +74,139 @@ use file::ZmqFile;
 ///     // tokio::reactor::run2(sink.send(msg.into())).unwrap();
 /// }
 /// ```
-pub struct MultipartSink {
-    inner: SinkState,
+/// The `B` type parameter picks the `SocketBackend` that actually hands `Multipart`s off to the
+/// socket; it defaults to `ReactorBackend`, the reactor-driven one. See `async::backend` for the
+/// `ThreadedSocket` alternative.
+///
+/// Because `B: SocketBackend` is `Unpin`, `MultipartSink` just stores it directly -- there's no
+/// placeholder state needed to guard taking it out of `&mut self`, and nothing to project
+/// through `Pin`.
+pub struct MultipartSink<B = ReactorBackend>
+where
+    B: SocketBackend,
+{
+    backend: B,
+    buffer: VecDeque<Multipart>,
+    capacity: usize,
 }
 
-pub(crate) enum SinkState {
-    Ready(zmq::Socket, PollEvented2<File<ZmqFile>>),
-    Pending(MultipartRequest<(zmq::Socket, PollEvented2<File<ZmqFile>>)>),
-    Polling,
+impl MultipartSink<ReactorBackend> {
+    /// Create a new `MultipartSink` with no internal buffering; `poll_ready` won't admit a new
+    /// `Multipart` until the previous one has finished sending.
+    pub fn new(sock: zmq::Socket, file: PollEvented2<File<ZmqFile>>) -> Self {
+        MultipartSink::with_capacity(sock, file, 0)
+    }
+
+    /// Create a new `MultipartSink` that buffers up to `capacity` outstanding `Multipart`s
+    /// in-memory before applying backpressure to the upstream producer.
+    pub fn with_capacity(sock: zmq::Socket, file: PollEvented2<File<ZmqFile>>, capacity: usize) -> Self {
+        MultipartSink::from_backend(ReactorBackend::new(sock, file), capacity)
+    }
 }
 
-impl MultipartSink {
-    pub fn new(sock: zmq::Socket, file: PollEvented2<File<ZmqFile>>) -> Self {
-        MultipartSink {
-            inner: SinkState::Ready(sock, file),
-        }
+#[cfg(feature = "threaded-backend")]
+impl MultipartSink<ThreadedSocket> {
+    /// Create a `MultipartSink` driven by a dedicated OS thread rather than a reactor, for use
+    /// under executors other than tokio.
+    pub fn with_threaded_backend(sock: zmq::Socket, capacity: usize) -> Self {
+        MultipartSink::from_backend(ThreadedSocket::spawn(sock), capacity)
     }
+}
 
-    pub(crate) fn take_socket(&mut self) -> Option<(zmq::Socket, PollEvented2<File<ZmqFile>>)> {
-        match self.polling() {
-            SinkState::Ready(sock, file) => Some((sock, file)),
-            SinkState::Pending(mut request) => {
-                let opt = request.take_socket();
-                self.inner = SinkState::Pending(request);
-                opt
-            }
-            SinkState::Polling => None,
+impl<B> MultipartSink<B>
+where
+    B: SocketBackend,
+{
+    pub(crate) fn from_backend(backend: B, capacity: usize) -> Self {
+        MultipartSink {
+            backend,
+            buffer: VecDeque::new(),
+            capacity,
         }
     }
 
-    pub(crate) fn give_socket(&mut self, sock: zmq::Socket, file: PollEvented2<File<ZmqFile>>) {
-        match self.polling() {
-            SinkState::Pending(mut request) => {
-                request.give_socket(sock, file);
-                self.inner = SinkState::Pending(request);
-            }
-            _ => self.inner = SinkState::Ready(sock, file),
-        }
+    /// Consume this sink, handing back the backend it was driving.
+    pub(crate) fn into_backend(self) -> B {
+        self.backend
     }
 
-    pub(crate) fn polling(&mut self) -> SinkState {
-        let mut state = SinkState::Polling;
+    /// If the backend is idle and a `Multipart` is buffered, kick off sending it.
+    fn drive_buffer(&mut self) -> Result<(), Error> {
+        if self.backend.is_sending() || self.buffer.is_empty() {
+            return Ok(());
+        }
 
-        swap(&mut state, &mut self.inner);
+        let multipart = self.buffer
+            .pop_front()
+            .expect("buffer was just checked to be non-empty");
 
-        state
+        self.backend.start_send(multipart)
     }
 
-    fn poll_request(
-        &mut self,
-        mut request: MultipartRequest<(zmq::Socket, PollEvented2<File<ZmqFile>>)>,
-        cx: &mut Context,
-    ) -> Result<Async<()>, Error> {
-        match request.poll(cx)? {
-            Async::Ready((sock, file)) => {
-                self.inner = SinkState::Ready(sock, file);
-
-                Ok(Async::Ready(()))
+    fn poll_flush_impl(&mut self, cx: &mut Context) -> Poll<Result<(), Error>> {
+        if self.backend.is_sending() {
+            match self.backend.poll_send(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
             }
-            Async::Pending => {
-                self.inner = SinkState::Pending(request);
+        }
 
-                Ok(Async::Pending)
-            }
+        if let Err(e) = self.drive_buffer() {
+            return Poll::Ready(Err(e));
         }
-    }
 
-    fn make_request(&mut self, multipart: Multipart) -> Result<(), Error> {
-        match self.polling() {
-            SinkState::Ready(sock, file) => {
-                self.inner = SinkState::Pending(MultipartRequest::new(sock, file, multipart));
-                Ok(())
-            }
-            _ => Err(Error::Sink),
+        if self.buffer.is_empty() && !self.backend.is_sending() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
         }
     }
 }
 
-impl Sink for MultipartSink {
-    type SinkItem = Multipart;
-    type SinkError = Error;
+impl<B> Sink<Multipart> for MultipartSink<B>
+where
+    B: SocketBackend,
+{
+    type Error = Error;
 
-    fn start_send(&mut self, multipart: Self::SinkItem) -> Result<(), Self::SinkError> {
-        self.make_request(multipart)?;
+    fn start_send(self: Pin<&mut Self>, multipart: Multipart) -> Result<(), Self::Error> {
+        let this = self.get_mut();
 
-        Ok(())
-    }
+        this.buffer.push_back(multipart);
+
+        debug_assert!(
+            this.buffer.len() <= this.capacity || !this.backend.is_sending(),
+            "start_send called while over capacity with a send already in flight; callers must \
+             respect poll_ready's backpressure"
+        );
 
-    fn poll_ready(&mut self, cx: &mut Context) -> Result<Async<()>, Self::SinkError> {
-        self.poll_flush(cx)
+        this.drive_buffer()
     }
 
-    fn poll_flush(&mut self, cx: &mut Context) -> Result<Async<()>, Self::SinkError> {
-        match self.polling() {
-            SinkState::Pending(request) => self.poll_request(request, cx),
-            SinkState::Ready(sock, file) => {
-                self.inner = SinkState::Ready(sock, file);
-                Ok(Async::Ready(()))
-            }
-            SinkState::Polling => Err(Error::Sink),
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+
+        match this.poll_flush_impl(cx) {
+            Poll::Ready(Ok(())) => return Poll::Ready(Ok(())),
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => (),
+        }
+
+        // Capacity 0 means "no extra buffering": only admit a new item once the in-flight
+        // request (if any) has drained.
+        if this.buffer.len() < this.capacity {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
         }
     }
 
-    fn poll_close(&mut self, cx: &mut Context) -> Result<Async<()>, Self::SinkError> {
-        self.poll_flush(cx)
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_flush_impl(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_flush_impl(cx)
     }
 }
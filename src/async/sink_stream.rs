@@ -20,16 +20,18 @@
 //! This module defines the `MultipartSinkStream` type. A wrapper around Sockets that implements
 //! `futures::Sink` and `futures::Stream`.
 
-use std::mem::swap;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
-use futures::{Async, Sink, Stream};
-use futures::task::Context;
+use futures::{Sink, Stream};
 use tokio_file_unix::File;
 use tokio::reactor::PollEvented2;
 use zmq;
 
-use async::sink::MultipartSink;
-use async::stream::MultipartStream;
+use async::backend::{ReactorBackend, SocketBackend};
+#[cfg(feature = "threaded-backend")]
+use async::threaded::ThreadedSocket;
 use error::Error;
 use file::ZmqFile;
 use message::Multipart;
@@ -45,16 +47,17 @@ use message::Multipart;
 /// #![feature(conservative_impl_trait)]
 ///
 /// extern crate zmq;
-/// extern crate futures;
-/// extern crate tokio;
+/// extern crate futures_util;
+/// extern crate futures_core;
 /// extern crate tokio_zmq;
 ///
 /// use std::sync::Arc;
 ///
-/// use futures::{FutureExt, Sink, Stream, StreamExt};
+/// use futures_util::{SinkExt, StreamExt};
+/// use futures_core::{Sink, Stream};
 /// use tokio_zmq::{Error, Multipart, Socket};
 ///
-/// fn get_sink_stream(socket: Socket) -> impl Sink<SinkItem = Multipart, SinkError = Error> + Stream<Item = Multipart, Error = Error>
+/// fn get_sink_stream(socket: Socket) -> impl Sink<Multipart, Error = Error> + Stream<Item = Result<Multipart, Error>>
 /// {
 ///     socket.sink_stream()
 /// }
@@ -73,214 +76,141 @@ use message::Multipart;
 ///     // tokio::reactor::run2(stream.forward(sink));
 /// }
 /// ```
-pub struct MultipartSinkStream {
-    inner: SinkStreamState,
+/// The sink half and the stream half of a `MultipartSinkStream` share the same underlying
+/// backend, so there's nothing to hand off between them -- the sink just keeps its own buffer
+/// alongside it, and the stream has no state of its own at all.
+///
+/// The `B` type parameter picks the `SocketBackend` that actually gets `Multipart`s on and off
+/// the socket; it defaults to `ReactorBackend`, the reactor-driven one. See `async::backend` for
+/// the `ThreadedSocket` alternative.
+pub struct MultipartSinkStream<B = ReactorBackend>
+where
+    B: SocketBackend,
+{
+    backend: B,
+    buffer: VecDeque<Multipart>,
+    capacity: usize,
 }
 
-enum SinkStreamState {
-    Sink(MultipartSink),
-    Stream(MultipartStream),
-    Both(
-        MultipartSink,
-        MultipartStream,
-        zmq::Socket,
-        PollEvented2<File<ZmqFile>>,
-    ),
-    Ready(zmq::Socket, PollEvented2<File<ZmqFile>>),
-    Polling,
+impl MultipartSinkStream<ReactorBackend> {
+    /// Create a new `MultipartSinkStream` with no internal buffering; `poll_ready` won't admit a
+    /// new `Multipart` until the previous one has finished sending.
+    pub fn new(sock: zmq::Socket, file: PollEvented2<File<ZmqFile>>) -> Self {
+        MultipartSinkStream::with_capacity(sock, file, 0)
+    }
+
+    /// Create a new `MultipartSinkStream` that buffers up to `capacity` outstanding `Multipart`s
+    /// in-memory before applying backpressure to the upstream producer, so a stream forwarded
+    /// into the sink half doesn't stall waiting for each message to finish sending.
+    pub fn with_capacity(sock: zmq::Socket, file: PollEvented2<File<ZmqFile>>, capacity: usize) -> Self {
+        MultipartSinkStream::from_backend(ReactorBackend::new(sock, file), capacity)
+    }
 }
 
-impl MultipartSinkStream {
-    pub fn new(sock: zmq::Socket, file: PollEvented2<File<ZmqFile>>) -> Self {
+#[cfg(feature = "threaded-backend")]
+impl MultipartSinkStream<ThreadedSocket> {
+    /// Create a `MultipartSinkStream` driven by a dedicated OS thread rather than a reactor, for
+    /// use under executors other than tokio.
+    pub fn with_threaded_backend(sock: zmq::Socket, capacity: usize) -> Self {
+        MultipartSinkStream::from_backend(ThreadedSocket::spawn(sock), capacity)
+    }
+}
+
+impl<B> MultipartSinkStream<B>
+where
+    B: SocketBackend,
+{
+    fn from_backend(backend: B, capacity: usize) -> Self {
         MultipartSinkStream {
-            inner: SinkStreamState::Ready(sock, file),
+            backend,
+            buffer: VecDeque::new(),
+            capacity,
         }
     }
 
-    fn polling(&mut self) -> SinkStreamState {
-        let mut state = SinkStreamState::Polling;
+    /// If the backend is idle and a `Multipart` is buffered, kick off sending it.
+    fn drive_buffer(&mut self) -> Result<(), Error> {
+        if self.backend.is_sending() || self.buffer.is_empty() {
+            return Ok(());
+        }
 
-        swap(&mut self.inner, &mut state);
+        let multipart = self.buffer
+            .pop_front()
+            .expect("buffer was just checked to be non-empty");
 
-        state
+        self.backend.start_send(multipart)
     }
 
-    fn poll_sink(
-        &mut self,
-        mut sink: MultipartSink,
-        stream: Option<MultipartStream>,
-        cx: &mut Context,
-    ) -> Result<Async<()>, Error> {
-        match sink.poll_flush(cx)? {
-            Async::Ready(_) => match sink.take_socket() {
-                Some((sock, file)) => {
-                    debug!("Released sink");
-                    match stream {
-                        Some(mut stream) => {
-                            stream.give_socket(sock, file);
-                            self.inner = SinkStreamState::Stream(stream);
-                        }
-                        None => {
-                            self.inner = SinkStreamState::Ready(sock, file);
-                        }
-                    }
-                    Ok(Async::Ready(()))
-                }
-                None => Err(Error::Sink),
-            },
-            Async::Pending => {
-                match stream {
-                    Some(mut stream) => match sink.take_socket() {
-                        Some((sock, file)) => {
-                            self.inner = SinkStreamState::Both(sink, stream, sock, file);
-                        }
-                        None => {
-                            return Err(Error::Sink);
-                        }
-                    },
-                    None => {
-                        self.inner = SinkStreamState::Sink(sink);
-                    }
-                }
-
-                Ok(Async::Pending)
+    fn poll_flush_impl(&mut self, cx: &mut Context) -> Poll<Result<(), Error>> {
+        if self.backend.is_sending() {
+            match self.backend.poll_send(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
             }
         }
-    }
 
-    fn poll_stream(
-        &mut self,
-        mut stream: MultipartStream,
-        sink: Option<MultipartSink>,
-        cx: &mut Context,
-    ) -> Result<Async<Option<Multipart>>, Error> {
-        match stream.poll_next(cx)? {
-            Async::Ready(item) => match stream.take_socket() {
-                Some((sock, file)) => {
-                    debug!("Released stream");
-                    match sink {
-                        Some(mut sink) => {
-                            sink.give_socket(sock, file);
-                            self.inner = SinkStreamState::Sink(sink);
-                        }
-                        None => {
-                            self.inner = SinkStreamState::Ready(sock, file);
-                        }
-                    }
-                    Ok(Async::Ready(item))
-                }
-                None => Err(Error::Stream),
-            },
-            Async::Pending => {
-                match sink {
-                    Some(mut sink) => match stream.take_socket() {
-                        Some((sock, file)) => {
-                            self.inner = SinkStreamState::Both(sink, stream, sock, file);
-                        }
-                        None => {
-                            return Err(Error::Stream);
-                        }
-                    },
-                    None => {
-                        self.inner = SinkStreamState::Stream(stream);
-                    }
-                }
+        if let Err(e) = self.drive_buffer() {
+            return Poll::Ready(Err(e));
+        }
 
-                Ok(Async::Pending)
-            }
+        if self.buffer.is_empty() && !self.backend.is_sending() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
         }
     }
 }
 
-impl Sink for MultipartSinkStream {
-    type SinkItem = Multipart;
-    type SinkError = Error;
+impl<B> Sink<Multipart> for MultipartSinkStream<B>
+where
+    B: SocketBackend,
+{
+    type Error = Error;
 
-    fn start_send(&mut self, multipart: Self::SinkItem) -> Result<(), Self::SinkError> {
-        debug!("Called start_send");
-        match self.polling() {
-            SinkStreamState::Ready(sock, file) => {
-                let mut sink = MultipartSink::new(sock, file);
-                sink.start_send(multipart)?;
-                self.inner = SinkStreamState::Sink(sink);
-                debug!("Created sink");
-                Ok(())
-            }
-            SinkStreamState::Stream(mut stream) => match stream.take_socket() {
-                Some((sock, file)) => {
-                    let mut sink = MultipartSink::new(sock, file);
-                    sink.start_send(multipart)?;
-                    match sink.take_socket() {
-                        Some((sock, file)) => {
-                            self.inner = SinkStreamState::Both(sink, stream, sock, file);
-                            debug!("Created sink");
-                            Ok(())
-                        }
-                        None => Err(Error::Sink),
-                    }
-                }
-                None => Err(Error::Sink),
-            },
-            _ => Err(Error::Sink),
-        }
-    }
+    fn start_send(self: Pin<&mut Self>, multipart: Multipart) -> Result<(), Self::Error> {
+        let this = self.get_mut();
 
-    fn poll_ready(&mut self, cx: &mut Context) -> Result<Async<()>, Self::SinkError> {
-        debug!("Called poll_ready");
-        self.poll_flush(cx)
+        this.buffer.push_back(multipart);
+        this.drive_buffer()
     }
 
-    fn poll_flush(&mut self, cx: &mut Context) -> Result<Async<()>, Self::SinkError> {
-        debug!("Called poll_flush");
-        match self.polling() {
-            SinkStreamState::Ready(sock, file) => {
-                self.inner = SinkStreamState::Ready(sock, file);
-                Ok(Async::Ready(()))
-            }
-            SinkStreamState::Sink(sink) => self.poll_sink(sink, None, cx),
-            SinkStreamState::Stream(stream) => {
-                self.inner = SinkStreamState::Stream(stream);
-                Ok(Async::Ready(()))
-            }
-            SinkStreamState::Both(mut sink, stream, sock, file) => {
-                sink.give_socket(sock, file);
-                self.poll_sink(sink, Some(stream), cx)
-            }
-            SinkStreamState::Polling => Err(Error::Sink),
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+
+        match this.poll_flush_impl(cx) {
+            Poll::Ready(Ok(())) => return Poll::Ready(Ok(())),
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => (),
+        }
+
+        // Capacity 0 means "no extra buffering": only admit a new item once the in-flight
+        // request (if any) has drained.
+        if this.buffer.len() < this.capacity {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
         }
     }
 
-    fn poll_close(&mut self, cx: &mut Context) -> Result<Async<()>, Self::SinkError> {
-        debug!("Called poll_close");
-        self.poll_flush(cx)
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_flush_impl(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_flush_impl(cx)
     }
 }
 
-impl Stream for MultipartSinkStream {
-    type Item = Multipart;
-    type Error = Error;
+impl<B> Stream for MultipartSinkStream<B>
+where
+    B: SocketBackend,
+{
+    type Item = Result<Multipart, Error>;
 
-    fn poll_next(&mut self, cx: &mut Context) -> Result<Async<Option<Multipart>>, Self::Error> {
-        match self.polling() {
-            SinkStreamState::Ready(sock, file) => {
-                let stream = MultipartStream::new(sock, file);
-                debug!("Created stream");
-                self.poll_stream(stream, None, cx)
-            }
-            SinkStreamState::Sink(mut sink) => match sink.take_socket() {
-                Some((sock, file)) => {
-                    let stream = MultipartStream::new(sock, file);
-                    debug!("Created stream");
-                    self.poll_stream(stream, Some(sink), cx)
-                }
-                None => Err(Error::Stream),
-            },
-            SinkStreamState::Both(sink, mut stream, sock, file) => {
-                stream.give_socket(sock, file);
-                self.poll_stream(stream, Some(sink), cx)
-            }
-            SinkStreamState::Stream(stream) => self.poll_stream(stream, None, cx),
-            SinkStreamState::Polling => Err(Error::Stream),
-        }
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        this.backend.poll_recv(cx).map(Some)
     }
 }
@@ -17,18 +17,22 @@
  * along with Tokio ZMQ.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::mem::swap;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::Duration;
 
-use futures::{Async, Future, Stream};
 use futures::future::Either;
-use futures::task::Context;
+use futures::Stream;
 use tokio::reactor::PollEvented2;
 use tokio_file_unix::File;
 use tokio_timer::{Sleep, Timer};
 use zmq;
 
-use async::future::MultipartResponse;
+use async::backend::{ReactorBackend, SocketBackend};
+#[cfg(feature = "threaded-backend")]
+use async::threaded::ThreadedSocket;
 use error::Error;
 use file::ZmqFile;
 use message::Multipart;
@@ -44,116 +48,89 @@ use prelude::{ControlHandler, EndHandler};
 /// #![feature(conservative_impl_trait)]
 ///
 /// extern crate zmq;
-/// extern crate futures;
-/// extern crate tokio_core;
+/// extern crate futures_core;
+/// extern crate tokio;
 /// extern crate tokio_zmq;
 ///
-/// use std::rc::Rc;
+/// use std::sync::Arc;
 ///
-/// use futures::Stream;
-/// use tokio_core::reactor::Core;
-/// use tokio_zmq::async::MultipartStream;
+/// use futures_core::Stream;
 /// use tokio_zmq::{Error, Multipart, Socket};
 ///
-/// fn get_stream(socket: Socket) -> impl Stream<Item = Multipart, Error = Error> {
-///     socket.stream().and_then(|multipart| {
-///         // handle multipart
-///         Ok(multipart)
-///     })
+/// fn get_stream(socket: Socket) -> impl Stream<Item = Result<Multipart, Error>> {
+///     socket.stream()
 /// }
 ///
 /// fn main() {
-///     let core = Core::new().unwrap();
-///     let context = Rc::new(zmq::Context::new());
-///     let socket = Socket::builder(context, &core.handle())
-///         .connect("tcp://localhost:5568")
-///         .filter(b"")
+///     let context = Arc::new(zmq::Context::new());
+///     let socket = Socket::builder(context)
+///         .bind("tcp://*:5569")
 ///         .build(zmq::SUB)
 ///         .unwrap();
-///     get_stream(socket);
+///     let stream = get_stream(socket);
+///
+///     // tokio::reactor::run2(stream.for_each(|_| Ok(())));
 /// }
 /// ```
-pub struct MultipartStream {
-    inner: StreamState,
-}
-
-pub(crate) enum StreamState {
-    Ready(zmq::Socket, PollEvented2<File<ZmqFile>>),
-    Pending(MultipartResponse),
-    Polling,
+/// The `B` type parameter picks the `SocketBackend` that actually gets `Multipart`s off the
+/// socket; it defaults to `ReactorBackend`, the reactor-driven one. See `async::backend` for the
+/// `ThreadedSocket` alternative.
+///
+/// Because `B: SocketBackend` is `Unpin`, `MultipartStream` just stores it directly -- there's no
+/// placeholder state needed to guard taking it out of `&mut self`, and nothing to project
+/// through `Pin`.
+pub struct MultipartStream<B = ReactorBackend>
+where
+    B: SocketBackend,
+{
+    backend: B,
 }
 
-impl MultipartStream {
+impl MultipartStream<ReactorBackend> {
     pub fn new(sock: zmq::Socket, file: PollEvented2<File<ZmqFile>>) -> Self {
-        MultipartStream {
-            inner: StreamState::Ready(sock, file),
-        }
+        MultipartStream::from_backend(ReactorBackend::new(sock, file))
     }
 
-    pub(crate) fn take_socket(&mut self) -> Option<(zmq::Socket, PollEvented2<File<ZmqFile>>)> {
-        match self.polling() {
-            StreamState::Ready(sock, file) => Some((sock, file)),
-            StreamState::Pending(mut response) => {
-                let opt = response.take_socket();
-                self.inner = StreamState::Pending(response);
-                opt
-            }
-            StreamState::Polling => None,
-        }
+    /// Borrow the raw socket this stream is driving, e.g. to change a sockopt at runtime without
+    /// tearing down the stream.
+    pub(crate) fn as_raw_socket(&self) -> &zmq::Socket {
+        self.backend.as_raw_socket()
     }
+}
 
-    pub(crate) fn give_socket(&mut self, sock: zmq::Socket, file: PollEvented2<File<ZmqFile>>) {
-        match self.polling() {
-            StreamState::Pending(mut response) => {
-                response.give_socket(sock, file);
-                self.inner = StreamState::Pending(response);
-            }
-            _ => self.inner = StreamState::Ready(sock, file),
-        }
+#[cfg(feature = "threaded-backend")]
+impl MultipartStream<ThreadedSocket> {
+    /// Create a `MultipartStream` driven by a dedicated OS thread rather than a reactor, for use
+    /// under executors other than tokio.
+    pub fn new_threaded(sock: zmq::Socket) -> Self {
+        MultipartStream::from_backend(ThreadedSocket::spawn(sock))
     }
+}
 
-    pub(crate) fn polling(&mut self) -> StreamState {
-        let mut state = StreamState::Polling;
-
-        swap(&mut self.inner, &mut state);
-
-        state
+impl<B> MultipartStream<B>
+where
+    B: SocketBackend,
+{
+    pub(crate) fn from_backend(backend: B) -> Self {
+        MultipartStream { backend }
     }
 
-    fn poll_response(
-        &mut self,
-        mut response: MultipartResponse,
-        cx: &mut Context,
-    ) -> Result<Async<Option<Multipart>>, Error> {
-        match response.poll(cx)? {
-            Async::Ready((item, sock, file)) => {
-                self.inner = StreamState::Ready(sock, file);
-
-                Ok(Async::Ready(Some(item)))
-            }
-            Async::Pending => {
-                self.inner = StreamState::Pending(response);
-
-                Ok(Async::Pending)
-            }
-        }
+    /// Consume this stream, handing back the backend it was driving.
+    pub(crate) fn into_backend(self) -> B {
+        self.backend
     }
 }
 
-impl Stream for MultipartStream {
-    type Item = Multipart;
-    type Error = Error;
+impl<B> Stream for MultipartStream<B>
+where
+    B: SocketBackend,
+{
+    type Item = Result<Multipart, Error>;
 
-    fn poll_next(&mut self, cx: &mut Context) -> Result<Async<Option<Multipart>>, Self::Error> {
-        match self.polling() {
-            StreamState::Ready(sock, file) => {
-                let response = MultipartResponse::new(sock, file);
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
 
-                self.poll_response(response, cx)
-            }
-            StreamState::Pending(response) => self.poll_response(response, cx),
-            StreamState::Polling => Err(Error::Stream),
-        }
+        this.backend.poll_recv(cx).map(Some)
     }
 }
 
@@ -161,7 +138,7 @@ impl Stream for MultipartStream {
 pub struct EndingStream<E, S>
 where
     E: EndHandler,
-    S: Stream<Item = Multipart, Error = Error>,
+    S: Stream<Item = Result<Multipart, Error>> + Unpin,
 {
     stream: S,
     // To handle stopping
@@ -171,7 +148,7 @@ where
 impl<E, S> EndingStream<E, S>
 where
     E: EndHandler,
-    S: Stream<Item = Multipart, Error = Error>,
+    S: Stream<Item = Result<Multipart, Error>> + Unpin,
 {
     /// Wrap a stream with an EndHandler
     pub fn new(stream: S, end_handler: E) -> Self
@@ -188,37 +165,38 @@ where
 impl<E, S> Stream for EndingStream<E, S>
 where
     E: EndHandler,
-    S: Stream<Item = Multipart, Error = Error>,
+    S: Stream<Item = Result<Multipart, Error>> + Unpin,
 {
-    type Item = Multipart;
-    type Error = Error;
-
-    fn poll_next(&mut self, cx: &mut Context) -> Result<Async<Option<Multipart>>, Error> {
-        let res = match self.stream.poll_next(cx)? {
-            Async::Ready(Some(item)) => if self.end_handler.should_stop(&item) {
-                Async::Ready(None)
-            } else {
-                Async::Ready(Some(item))
-            },
-            Async::Ready(None) => Async::Ready(None),
-            Async::Pending => Async::Pending,
-        };
-
-        Ok(res)
+    type Item = Result<Multipart, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        match Pin::new(&mut this.stream).poll_next(cx) {
+            Poll::Ready(Some(Ok(item))) => {
+                if this.end_handler.should_stop(&item) {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Ready(Some(Ok(item)))
+                }
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
     }
 }
 
 /// `ControlledStream`s are used when you want a stream of multiparts, but you want to be able to
 /// turn it off.
 ///
-/// It contains a handler that implements the `ControlHandler` trait. This trait contains a single
-/// method `should_stop`, that determines whether or not the given stream should stop producing
-/// values.
+/// The `control` stream decides when `stream` should stop; once `control` ends, or produces a
+/// multipart the `handler` flags, the combined stream ends too.
 pub struct ControlledStream<H, S, T>
 where
     H: ControlHandler,
-    S: Stream<Item = Multipart, Error = Error>,
-    T: Stream<Item = Multipart, Error = Error>,
+    S: Stream<Item = Result<Multipart, Error>> + Unpin,
+    T: Stream<Item = Result<Multipart, Error>> + Unpin,
 {
     stream: T,
     control: S,
@@ -228,13 +206,12 @@ where
 impl<H, S, T> ControlledStream<H, S, T>
 where
     H: ControlHandler,
-    S: Stream<Item = Multipart, Error = Error>,
-    T: Stream<Item = Multipart, Error = Error>,
+    S: Stream<Item = Result<Multipart, Error>> + Unpin,
+    T: Stream<Item = Result<Multipart, Error>> + Unpin,
 {
     /// Create a new ControlledStream.
     ///
-    /// This shouldn't be called directly. A socket wrapper type's `controlled` method, if present,
-    /// will perform the required actions to create and encapsulate this type.
+    /// `stream` produces the values this stream yields; `control` decides when to stop.
     pub fn new(stream: T, control: S, handler: H) -> ControlledStream<H, S, T> {
         ControlledStream {
             stream,
@@ -247,32 +224,92 @@ where
 impl<H, S, T> Stream for ControlledStream<H, S, T>
 where
     H: ControlHandler,
-    S: Stream<Item = Multipart, Error = Error>,
-    T: Stream<Item = Multipart, Error = Error>,
+    S: Stream<Item = Result<Multipart, Error>> + Unpin,
+    T: Stream<Item = Result<Multipart, Error>> + Unpin,
 {
-    type Item = Multipart;
-    type Error = Error;
+    type Item = Result<Multipart, Error>;
 
     /// Poll the control stream, if it isn't ready, poll the producing stream
-    ///
-    /// If the control stream is ready, but has ended, stop the producting stream.
-    /// If the control stream is ready with a Multipart, use the `ControlHandler`
-    /// to determine if the producting stream should be stopped.
-    fn poll_next(&mut self, cx: &mut Context) -> Result<Async<Option<Multipart>>, Error> {
-        let stop = match self.control.poll_next(cx)? {
-            Async::Pending => false,
-            Async::Ready(None) => true,
-            Async::Ready(Some(multipart)) => self.handler.should_stop(multipart),
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        let stop = match Pin::new(&mut this.control).poll_next(cx) {
+            Poll::Pending => false,
+            Poll::Ready(None) => true,
+            Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+            Poll::Ready(Some(Ok(multipart))) => this.handler.should_stop(multipart),
         };
 
         if stop {
-            Ok(Async::Ready(None))
+            Poll::Ready(None)
         } else {
-            self.stream.poll_next(cx)
+            Pin::new(&mut this.stream).poll_next(cx)
+        }
+    }
+}
+
+/// A stream that stops once a signal `Future` resolves, instead of once a control `Stream`
+/// produces a value the way `ControlledStream` does.
+///
+/// This is useful for stopping a stream based on a one-off event, such as a Ctrl-C handler.
+pub struct SignalControlledStream<F, T>
+where
+    F: Future<Output = Result<(), Error>> + Unpin,
+    T: Stream<Item = Result<Multipart, Error>> + Unpin,
+{
+    stream: T,
+    signal: Option<F>,
+    stopped: bool,
+}
+
+impl<F, T> SignalControlledStream<F, T>
+where
+    F: Future<Output = Result<(), Error>> + Unpin,
+    T: Stream<Item = Result<Multipart, Error>> + Unpin,
+{
+    /// Create a new SignalControlledStream.
+    ///
+    /// `stream` produces the values this stream yields; once `signal` resolves, the stream ends.
+    pub fn new(stream: T, signal: F) -> Self {
+        SignalControlledStream {
+            stream,
+            signal: Some(signal),
+            stopped: false,
         }
     }
 }
 
+impl<F, T> Stream for SignalControlledStream<F, T>
+where
+    F: Future<Output = Result<(), Error>> + Unpin,
+    T: Stream<Item = Result<Multipart, Error>> + Unpin,
+{
+    type Item = Result<Multipart, Error>;
+
+    /// Poll the signal future, if present, and stop producing values once it resolves.
+    /// Otherwise, poll the producing stream.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.stopped {
+            return Poll::Ready(None);
+        }
+
+        if let Some(mut signal) = this.signal.take() {
+            match Pin::new(&mut signal).poll(cx) {
+                Poll::Ready(Ok(())) => {
+                    this.stopped = true;
+                    return Poll::Ready(None);
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+                Poll::Pending => this.signal = Some(signal),
+            }
+        }
+
+        Pin::new(&mut this.stream).poll_next(cx)
+    }
+}
+
 /// An empty type to represent a timeout event
 pub struct Timeout;
 
@@ -291,7 +328,7 @@ where
 
 impl<S> TimeoutStream<S>
 where
-    S: Stream<Error = Error>,
+    S: Stream + Unpin,
 {
     /// Add a timeout to a stream
     pub fn new(stream: S, duration: Duration) -> Self {
@@ -307,26 +344,124 @@ where
     }
 }
 
-impl<S> Stream for TimeoutStream<S>
+impl<S, T> Stream for TimeoutStream<S>
 where
-    S: Stream<Error = Error>,
+    S: Stream<Item = Result<T, Error>> + Unpin,
 {
-    type Item = Either<S::Item, Timeout>;
-    type Error = Error;
+    type Item = Result<Either<T, Timeout>, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
 
-    fn poll_next(&mut self, cx: &mut Context) -> Result<Async<Option<Self::Item>>, Self::Error> {
-        if let Async::Ready(_) = self.timeout.poll(cx)? {
-            self.timeout = self.timer.sleep(self.duration);
+        match Pin::new(&mut this.timeout).poll(cx) {
+            Poll::Ready(Ok(())) => {
+                this.timeout = this.timer.sleep(this.duration);
 
-            return Ok(Async::Ready(Some(Either::Right(Timeout))));
+                return Poll::Ready(Some(Ok(Either::Right(Timeout))));
+            }
+            Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+            Poll::Pending => (),
         }
 
-        let res = match self.stream.poll_next(cx)? {
-            Async::Ready(Some(item)) => Async::Ready(Some(Either::Left(item))),
-            Async::Ready(None) => Async::Ready(None),
-            Async::Pending => Async::Pending,
-        };
+        match Pin::new(&mut this.stream).poll_next(cx) {
+            Poll::Ready(Some(Ok(item))) => Poll::Ready(Some(Ok(Either::Left(item)))),
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// A stream that coalesces polls of its underlying stream, for use when messages arrive fast
+/// enough that polling (and waking) once per message becomes a bottleneck.
+///
+/// Rather than waking the task for every individual item, `ThrottlingStream` wakes at most once
+/// per `duration` and drains up to `max_batch` items from the underlying stream at a time,
+/// serving them from an internal buffer in between.
+pub struct ThrottlingStream<S>
+where
+    S: Stream,
+{
+    stream: S,
+    buffer: VecDeque<S::Item>,
+    max_batch: usize,
+    duration: Duration,
+    timer: Timer,
+    timeout: Sleep,
+    done: bool,
+}
+
+impl<S> ThrottlingStream<S>
+where
+    S: Stream + Unpin,
+{
+    /// Throttle `stream`, draining up to `max_batch` items from it at most once per `duration`.
+    pub fn new(stream: S, duration: Duration, max_batch: usize) -> Self {
+        let timer = Timer::default();
+        let timeout = timer.sleep(duration);
 
-        Ok(res)
+        ThrottlingStream {
+            stream,
+            buffer: VecDeque::new(),
+            max_batch,
+            duration,
+            timer,
+            timeout,
+            done: false,
+        }
+    }
+
+    /// Poll the underlying stream until it's `Pending`, `max_batch` items have been buffered, or
+    /// it ends, buffering any `Ready` items along the way.
+    fn drain(&mut self, cx: &mut Context) {
+        while self.buffer.len() < self.max_batch {
+            match Pin::new(&mut self.stream).poll_next(cx) {
+                Poll::Ready(Some(item)) => self.buffer.push_back(item),
+                Poll::Ready(None) => {
+                    self.done = true;
+                    break;
+                }
+                Poll::Pending => break,
+            }
+        }
+    }
+}
+
+impl<S, T> Stream for ThrottlingStream<S>
+where
+    S: Stream<Item = Result<T, Error>> + Unpin,
+{
+    type Item = Result<T, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if let Some(item) = this.buffer.pop_front() {
+            return Poll::Ready(Some(item));
+        }
+
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        match Pin::new(&mut this.timeout).poll(cx) {
+            Poll::Ready(Ok(())) => {
+                this.timeout = this.timer.sleep(this.duration);
+                this.drain(cx);
+            }
+            Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
+            Poll::Pending => return Poll::Pending,
+        }
+
+        match this.buffer.pop_front() {
+            Some(item) => Poll::Ready(Some(item)),
+            None => {
+                if this.done {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Pending
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,307 @@
+/*
+ * This file is part of Tokio ZMQ.
+ *
+ * Copyright © 2017 Riley Trautman
+ *
+ * Tokio ZMQ is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Tokio ZMQ is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Tokio ZMQ.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! An executor-agnostic backend for `MultipartStream`/`MultipartSink`, and for one-shot
+//! sends/receives via `ThreadedRequest`/`ThreadedResponse`.
+//!
+//! Instead of registering the socket's file descriptor with a reactor via `PollEvented2`, this
+//! backend hands the `zmq::Socket` to a dedicated OS thread that takes commands off an `mpsc`
+//! channel one at a time, performs each as a blocking `recv` or `send`, and shuttles `Multipart`s
+//! across a pair of `mpsc` channels, waking whichever task is waiting via a stashed
+//! `std::task::Waker`. Commands run strictly in order, so a send queued behind an outstanding
+//! receive doesn't start until that receive has a message. It implements `async::backend::SocketBackend`, so it's usable anywhere
+//! `MultipartStream`/`MultipartSink` are, under any executor, and on platforms where polling the
+//! ZMQ FD directly through mio is unreliable.
+//!
+//! `ThreadedRequest`/`ThreadedResponse` are the `ThreadedSocket` counterparts to
+//! `MultipartRequest`/`MultipartResponse`. They don't share an abstraction with those types: a
+//! `MultipartRequest<T>` hands the raw socket back to the caller as a typed `T` once it's done,
+//! but a `ThreadedSocket` never gives the `zmq::Socket` back (the background thread keeps it for
+//! as long as the handle lives), so these resolve to another `ThreadedSocket` instead.
+//!
+//! Enable the `threaded-backend` feature to build this module.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use zmq;
+
+use async::backend::SocketBackend;
+use error::Error;
+use message::Multipart;
+
+enum Command {
+    Recv,
+    Send(Multipart),
+}
+
+enum Event {
+    Received(Multipart),
+    Sent,
+    Error(Error),
+}
+
+/// A handle to a `zmq::Socket` owned and driven by a dedicated background thread.
+///
+/// The thread blocks on its command channel waiting for the next `Command`, performs the corresponding
+/// blocking `recv`/`send`, and replies with an `Event`, waking the task registered via
+/// `poll_recv`/`poll_send` so it knows to check the channel again.
+pub struct ThreadedSocket {
+    commands: Sender<Command>,
+    events: Receiver<Event>,
+    waker: Arc<Mutex<Option<Waker>>>,
+    recv_pending: bool,
+    send_pending: bool,
+}
+
+impl ThreadedSocket {
+    /// Move `sock` onto a new background thread and return a handle to it.
+    pub(crate) fn spawn(sock: zmq::Socket) -> Self {
+        let (command_tx, command_rx) = mpsc::channel();
+        let (event_tx, event_rx) = mpsc::channel();
+        let waker = Arc::new(Mutex::new(None));
+        let thread_waker = Arc::clone(&waker);
+
+        thread::spawn(move || Self::run(sock, command_rx, event_tx, thread_waker));
+
+        ThreadedSocket {
+            commands: command_tx,
+            events: event_rx,
+            waker,
+            recv_pending: false,
+            send_pending: false,
+        }
+    }
+
+    fn run(
+        sock: zmq::Socket,
+        commands: Receiver<Command>,
+        events: Sender<Event>,
+        waker: Arc<Mutex<Option<Waker>>>,
+    ) {
+        for command in commands {
+            let event = match command {
+                Command::Recv => Self::blocking_recv(&sock),
+                Command::Send(multipart) => Self::blocking_send(&sock, multipart),
+            };
+
+            if events.send(event).is_err() {
+                // The handle was dropped; no one is listening any more.
+                return;
+            }
+
+            if let Some(waker) = waker
+                .lock()
+                .expect("ThreadedSocket: waker lock poisoned")
+                .take()
+            {
+                waker.wake();
+            }
+        }
+    }
+
+    fn blocking_recv(sock: &zmq::Socket) -> Event {
+        let mut multipart = Multipart::new();
+
+        loop {
+            let mut msg = match zmq::Message::new() {
+                Ok(msg) => msg,
+                Err(e) => return Event::Error(e.into()),
+            };
+
+            if let Err(e) = sock.recv(&mut msg, 0) {
+                return Event::Error(e.into());
+            }
+
+            let more = msg.get_more();
+            multipart.push_back(msg);
+
+            if !more {
+                return Event::Received(multipart);
+            }
+        }
+    }
+
+    fn blocking_send(sock: &zmq::Socket, mut multipart: Multipart) -> Event {
+        while let Some(msg) = multipart.pop_front() {
+            let flags = if multipart.is_empty() { 0 } else { zmq::SNDMORE };
+
+            if let Err(e) = sock.send_msg(msg, flags) {
+                return Event::Error(Error::MultipartSend(multipart, e));
+            }
+        }
+
+        Event::Sent
+    }
+
+    fn register(&self, cx: &mut Context) {
+        *self.waker.lock().expect("ThreadedSocket: waker lock poisoned") = Some(cx.waker().clone());
+    }
+
+    /// The one-shot, thread-backed analog of `Socket::send`: hand `multipart` to the background
+    /// thread and resolve once it's been sent. Unlike `Socket::send`, there's no `PollEvented2`-
+    /// backed socket to hand back into a typed wrapper afterward, so the future resolves to the
+    /// `ThreadedSocket` itself, ready for another `send`/`recv`.
+    pub fn send(self, multipart: Multipart) -> ThreadedRequest {
+        ThreadedRequest::new(self, multipart)
+    }
+
+    /// The one-shot, thread-backed analog of `Socket::recv`.
+    pub fn recv(self) -> ThreadedResponse {
+        ThreadedResponse::new(self)
+    }
+}
+
+impl SocketBackend for ThreadedSocket {
+    /// Ask the background thread for the next `Multipart`, if a request isn't already in flight.
+    fn poll_recv(&mut self, cx: &mut Context) -> Poll<Result<Multipart, Error>> {
+        self.register(cx);
+
+        if !self.recv_pending {
+            if self.commands.send(Command::Recv).is_err() {
+                return Poll::Ready(Err(Error::Reused));
+            }
+            self.recv_pending = true;
+        }
+
+        match self.events.try_recv() {
+            Ok(Event::Received(multipart)) => {
+                self.recv_pending = false;
+                Poll::Ready(Ok(multipart))
+            }
+            Ok(Event::Error(e)) => {
+                self.recv_pending = false;
+                Poll::Ready(Err(e))
+            }
+            Ok(Event::Sent) => unreachable!("ThreadedSocket: got a Sent event while receiving"),
+            Err(TryRecvError::Empty) => Poll::Pending,
+            Err(TryRecvError::Disconnected) => Poll::Ready(Err(Error::Reused)),
+        }
+    }
+
+    /// Hand `multipart` to the background thread to send. Only call this when no send is already
+    /// in flight.
+    fn start_send(&mut self, multipart: Multipart) -> Result<(), Error> {
+        self.commands
+            .send(Command::Send(multipart))
+            .map_err(|_| Error::Reused)?;
+        self.send_pending = true;
+
+        Ok(())
+    }
+
+    /// Poll for the completion of a `send` started via `start_send`.
+    fn poll_send(&mut self, cx: &mut Context) -> Poll<Result<(), Error>> {
+        self.register(cx);
+
+        match self.events.try_recv() {
+            Ok(Event::Sent) => {
+                self.send_pending = false;
+                Poll::Ready(Ok(()))
+            }
+            Ok(Event::Error(e)) => {
+                self.send_pending = false;
+                Poll::Ready(Err(e))
+            }
+            Ok(Event::Received(_)) => unreachable!("ThreadedSocket: got a Received event while sending"),
+            Err(TryRecvError::Empty) => Poll::Pending,
+            Err(TryRecvError::Disconnected) => Poll::Ready(Err(Error::Reused)),
+        }
+    }
+
+    fn is_sending(&self) -> bool {
+        self.send_pending
+    }
+}
+
+/// The `Future` returned by `ThreadedSocket::send`.
+pub struct ThreadedRequest {
+    backend: Option<ThreadedSocket>,
+    multipart: Option<Multipart>,
+}
+
+impl ThreadedRequest {
+    pub(crate) fn new(backend: ThreadedSocket, multipart: Multipart) -> Self {
+        ThreadedRequest {
+            backend: Some(backend),
+            multipart: Some(multipart),
+        }
+    }
+}
+
+impl Future for ThreadedRequest {
+    type Output = Result<ThreadedSocket, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = Pin::into_inner(self);
+
+        let backend = match this.backend.as_mut() {
+            Some(backend) => backend,
+            None => return Poll::Ready(Err(Error::Reused)),
+        };
+
+        if let Some(multipart) = this.multipart.take() {
+            if let Err(e) = backend.start_send(multipart) {
+                return Poll::Ready(Err(e));
+            }
+        }
+
+        match backend.poll_send(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(this.backend.take().unwrap())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// The `Future` returned by `ThreadedSocket::recv`.
+pub struct ThreadedResponse {
+    backend: Option<ThreadedSocket>,
+}
+
+impl ThreadedResponse {
+    pub(crate) fn new(backend: ThreadedSocket) -> Self {
+        ThreadedResponse {
+            backend: Some(backend),
+        }
+    }
+}
+
+impl Future for ThreadedResponse {
+    type Output = Result<(ThreadedSocket, Multipart), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = Pin::into_inner(self);
+
+        let backend = match this.backend.as_mut() {
+            Some(backend) => backend,
+            None => return Poll::Ready(Err(Error::Reused)),
+        };
+
+        match backend.poll_recv(cx) {
+            Poll::Ready(Ok(multipart)) => Poll::Ready(Ok((this.backend.take().unwrap(), multipart))),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,264 @@
+/*
+ * This file is part of Tokio ZMQ.
+ *
+ * Copyright © 2017 Riley Trautman
+ *
+ * Tokio ZMQ is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Tokio ZMQ is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Tokio ZMQ.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! This module defines the `Bridge` type, a future that gateways `Multipart`s between a ZeroMQ
+//! socket and an arbitrary external `Sink`/`Stream` pair (e.g. an MQTT or WebSocket connection).
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::{Future, Sink, Stream};
+
+use async::MultipartSinkStream;
+use error::Error;
+use message::Multipart;
+use proxy::NoControl;
+use prelude::ControlHandler;
+
+/// Controls what `Bridge` does when the external sink can't keep up with the ZMQ side.
+pub enum BackpressurePolicy {
+    /// Don't buffer at all; stop reading from the ZMQ side until the external sink has accepted
+    /// the item currently in flight.
+    Block,
+    /// Buffer up to `usize` outstanding items, then fall back to `Block`'s behavior.
+    Bounded(usize),
+    /// Buffer up to `usize` outstanding items, dropping the oldest buffered item to make room for
+    /// a new one instead of ever blocking the ZMQ side.
+    DropOldest(usize),
+}
+
+impl BackpressurePolicy {
+    fn capacity(&self) -> usize {
+        match *self {
+            BackpressurePolicy::Block => 0,
+            BackpressurePolicy::Bounded(capacity) | BackpressurePolicy::DropOldest(capacity) => {
+                capacity
+            }
+        }
+    }
+
+    fn drops_oldest(&self) -> bool {
+        match *self {
+            BackpressurePolicy::DropOldest(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// A gateway between a ZeroMQ socket and an external `Sink`/`Stream` pair.
+///
+/// `Bridge` forwards every `Multipart` the ZMQ side produces to `ext`, transformed by `to_ext`,
+/// and forwards everything `ext` produces back to the ZMQ side, transformed by `from_ext`. This
+/// turns the hand-written `map`/`forward` relay loops applications write to gateway ZMQ traffic
+/// onto other protocols into a single, reusable future.
+///
+/// `Item` is the type `ext`'s `Sink` half accepts (`E::SinkItem`, back when `Sink` had an
+/// associated item type); now that `Sink` takes its item as a type parameter, `Bridge` has to name
+/// it the same way.
+pub struct Bridge<E, ToExt, FromExt, Item, C = NoControl, H = NoControl>
+where
+    E: Stream + Sink<Item>,
+{
+    zmq: MultipartSinkStream,
+    ext: E,
+    to_ext: ToExt,
+    from_ext: FromExt,
+    policy: BackpressurePolicy,
+    buffer: VecDeque<Item>,
+    control: Option<(C, H)>,
+}
+
+impl<E, ToExt, FromExt, Item> Bridge<E, ToExt, FromExt, Item, NoControl, NoControl>
+where
+    E: Stream + Sink<Item>,
+    E::Error: Into<Error>,
+    ToExt: FnMut(Multipart) -> Item,
+    FromExt: FnMut(E::Item) -> Multipart,
+{
+    /// Create a new `Bridge` gatewaying between `zmq` and `ext`.
+    pub fn new(zmq: MultipartSinkStream, ext: E, to_ext: ToExt, from_ext: FromExt, policy: BackpressurePolicy) -> Self {
+        Bridge {
+            zmq,
+            ext,
+            to_ext,
+            from_ext,
+            policy,
+            buffer: VecDeque::new(),
+            control: None,
+        }
+    }
+
+    /// Allow `control` to stop the bridge early, using `handler` to decide when to stop.
+    pub fn controlled<C, H>(self, control: C, handler: H) -> Bridge<E, ToExt, FromExt, Item, C, H>
+    where
+        C: Stream<Item = Result<Multipart, Error>> + Unpin,
+        H: ControlHandler,
+    {
+        Bridge {
+            zmq: self.zmq,
+            ext: self.ext,
+            to_ext: self.to_ext,
+            from_ext: self.from_ext,
+            policy: self.policy,
+            buffer: self.buffer,
+            control: Some((control, handler)),
+        }
+    }
+}
+
+impl<E, ToExt, FromExt, Item, C, H> Bridge<E, ToExt, FromExt, Item, C, H>
+where
+    E: Stream + Sink<Item> + Unpin,
+    E::Error: Into<Error>,
+    ToExt: FnMut(Multipart) -> Item,
+    FromExt: FnMut(E::Item) -> Multipart,
+    C: Stream<Item = Result<Multipart, Error>> + Unpin,
+    H: ControlHandler,
+{
+    fn poll_control(&mut self, cx: &mut Context) -> Result<bool, Error> {
+        let stop = match self.control {
+            Some((ref mut control, ref mut handler)) => match Pin::new(control).poll_next(cx) {
+                Poll::Ready(None) => true,
+                Poll::Ready(Some(Ok(multipart))) => handler.should_stop(multipart),
+                Poll::Ready(Some(Err(e))) => return Err(e),
+                Poll::Pending => false,
+            },
+            None => false,
+        };
+
+        Ok(stop)
+    }
+
+    /// Pull a `Multipart` off the ZMQ side and buffer it (subject to `policy`) for `ext`.
+    fn poll_zmq_to_ext(&mut self, cx: &mut Context) -> Result<bool, Error> {
+        if !self.policy.drops_oldest() && self.buffer.len() > self.policy.capacity() {
+            return Ok(false);
+        }
+
+        match Pin::new(&mut self.zmq).poll_next(cx) {
+            Poll::Ready(Some(Ok(multipart))) => {
+                if self.policy.drops_oldest() && self.buffer.len() >= self.policy.capacity() {
+                    self.buffer.pop_front();
+                }
+
+                self.buffer.push_back((self.to_ext)(multipart));
+
+                Ok(true)
+            }
+            Poll::Ready(Some(Err(e))) => Err(e),
+            Poll::Ready(None) => Ok(false),
+            Poll::Pending => Ok(false),
+        }
+    }
+
+    /// Hand the oldest buffered item to `ext`, if there is one it can accept right now.
+    fn drive_ext_sink(&mut self, cx: &mut Context) -> Result<(), Error> {
+        if !self.buffer.is_empty() {
+            match Pin::new(&mut self.ext).poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    let item = self.buffer
+                        .pop_front()
+                        .expect("buffer was just checked to be non-empty");
+                    Pin::new(&mut self.ext).start_send(item).map_err(Into::into)?;
+                }
+                Poll::Ready(Err(e)) => return Err(e.into()),
+                Poll::Pending => (),
+            }
+        }
+
+        if let Poll::Ready(Err(e)) = Pin::new(&mut self.ext).poll_flush(cx) {
+            return Err(e.into());
+        }
+
+        Ok(())
+    }
+
+    /// Pull an item off `ext` and forward it, transformed, to the ZMQ side.
+    fn poll_ext_to_zmq(&mut self, cx: &mut Context) -> Result<bool, Error> {
+        match Pin::new(&mut self.zmq).poll_ready(cx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(e)) => return Err(e),
+            Poll::Pending => return Ok(false),
+        }
+
+        match Pin::new(&mut self.ext).poll_next(cx) {
+            Poll::Ready(Some(item)) => {
+                let multipart = (self.from_ext)(item);
+
+                Pin::new(&mut self.zmq).start_send(multipart)?;
+                if let Poll::Ready(Err(e)) = Pin::new(&mut self.zmq).poll_flush(cx) {
+                    return Err(e);
+                }
+
+                Ok(true)
+            }
+            Poll::Ready(None) => Ok(false),
+            Poll::Pending => Ok(false),
+        }
+    }
+}
+
+impl<E, ToExt, FromExt, Item, C, H> Future for Bridge<E, ToExt, FromExt, Item, C, H>
+where
+    E: Stream + Sink<Item> + Unpin,
+    E::Error: Into<Error>,
+    ToExt: FnMut(Multipart) -> Item,
+    FromExt: FnMut(E::Item) -> Multipart,
+    C: Stream<Item = Result<Multipart, Error>> + Unpin,
+    H: ControlHandler,
+{
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            match this.poll_control(cx) {
+                Ok(true) => {
+                    if let Poll::Ready(Err(e)) = Pin::new(&mut this.zmq).poll_close(cx) {
+                        return Poll::Ready(Err(e));
+                    }
+                    if let Poll::Ready(Err(e)) = Pin::new(&mut this.ext).poll_close(cx) {
+                        return Poll::Ready(Err(e.into()));
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(false) => (),
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+
+            let zmq_to_ext = match this.poll_zmq_to_ext(cx) {
+                Ok(progress) => progress,
+                Err(e) => return Poll::Ready(Err(e)),
+            };
+            if let Err(e) = this.drive_ext_sink(cx) {
+                return Poll::Ready(Err(e));
+            }
+            let ext_to_zmq = match this.poll_ext_to_zmq(cx) {
+                Ok(progress) => progress,
+                Err(e) => return Poll::Ready(Err(e)),
+            };
+
+            if !zmq_to_ext && !ext_to_zmq {
+                return Poll::Pending;
+            }
+        }
+    }
+}
@@ -0,0 +1,335 @@
+/*
+ * This file is part of Tokio ZMQ.
+ *
+ * Copyright © 2017 Riley Trautman
+ *
+ * Tokio ZMQ is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Tokio ZMQ is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Tokio ZMQ.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! This module defines `Client` and `Server`, high-level wrappers around `Req`/`Rep`-style
+//! sockets that hide the `send`/`recv`/`sink_stream` plumbing for the common RPC case. If you
+//! need the raw `sink_stream`/`split`/`forward` API, the wrapper types still expose it directly.
+
+use std::mem;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::future::{self, Either};
+use futures::{Future, FutureExt, StreamExt, TryStreamExt};
+use tokio_timer::{Sleep, Timer};
+
+use async::{MultipartRequest, MultipartResponse};
+use error::Error;
+use message::Multipart;
+use prelude::{SinkSocket, SinkStreamSocket, StreamSocket};
+
+/// A high-level request/reply client.
+///
+/// `Client` wraps a socket implementing both `StreamSocket` and `SinkSocket` (typically `Req`)
+/// and enforces the send-then-recv lock-step internally, so callers just call `request` with a
+/// `Multipart` and get a `Multipart` back.
+pub struct Client<R>
+where
+    R: StreamSocket + SinkSocket,
+{
+    inner: Rc<RefCell<Option<R>>>,
+}
+
+impl<R> Client<R>
+where
+    R: StreamSocket + SinkSocket,
+{
+    /// Wrap a socket (typically a `Req`) in a `Client`.
+    pub fn new(socket: R) -> Self {
+        Client {
+            inner: Rc::new(RefCell::new(Some(socket))),
+        }
+    }
+
+    /// Perform a single request/reply round-trip.
+    ///
+    /// ### Panics
+    ///
+    /// Panics if called again before a previous `request`'s future has resolved; REQ sockets
+    /// only ever have one outstanding request at a time.
+    pub fn request(&self, multipart: Multipart) -> ClientRequest<R> {
+        let socket = self.inner
+            .borrow_mut()
+            .take()
+            .expect("Client::request called while a previous request is still outstanding");
+
+        ClientRequest {
+            shared: Rc::clone(&self.inner),
+            state: ClientRequestState::Sending(socket.send(multipart)),
+        }
+    }
+}
+
+enum ClientRequestState<R>
+where
+    R: StreamSocket + SinkSocket,
+{
+    Sending(MultipartRequest<R>),
+    Receiving(MultipartResponse<R>),
+    Done,
+}
+
+/// The `Future` returned by `Client::request`.
+pub struct ClientRequest<R>
+where
+    R: StreamSocket + SinkSocket,
+{
+    shared: Rc<RefCell<Option<R>>>,
+    state: ClientRequestState<R>,
+}
+
+impl<R> Future for ClientRequest<R>
+where
+    R: StreamSocket + SinkSocket,
+{
+    type Output = Result<Multipart, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            match mem::replace(&mut this.state, ClientRequestState::Done) {
+                ClientRequestState::Sending(mut fut) => match Pin::new(&mut fut).poll(cx) {
+                    Poll::Ready(Ok(socket)) => {
+                        this.state = ClientRequestState::Receiving(socket.recv());
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => {
+                        this.state = ClientRequestState::Sending(fut);
+                        return Poll::Pending;
+                    }
+                },
+                ClientRequestState::Receiving(mut fut) => match Pin::new(&mut fut).poll(cx) {
+                    Poll::Ready(Ok((multipart, socket))) => {
+                        *this.shared.borrow_mut() = Some(socket);
+                        return Poll::Ready(Ok(multipart));
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => {
+                        this.state = ClientRequestState::Receiving(fut);
+                        return Poll::Pending;
+                    }
+                },
+                ClientRequestState::Done => return Poll::Ready(Err(Error::Reused)),
+            }
+        }
+    }
+}
+
+/// A high-level request/reply server.
+///
+/// `Server` wraps a socket implementing both `StreamSocket` and `SinkSocket` (typically `Rep`)
+/// and drives a receive -> process -> reply loop, calling `handler` for every incoming
+/// `Multipart` and sending back whatever `Multipart` it resolves to.
+pub struct Server<R, H> {
+    socket: R,
+    handler: H,
+}
+
+impl<R, H, F> Server<R, H>
+where
+    R: StreamSocket + SinkSocket,
+    H: FnMut(Multipart) -> F,
+    F: Future<Output = Result<Multipart, Error>>,
+{
+    /// Wrap a socket (typically a `Rep`) and a per-request handler in a `Server`.
+    pub fn new(socket: R, handler: H) -> Self {
+        Server { socket, handler }
+    }
+
+    /// Run the server until the underlying stream ends or errors, handling exactly one request at
+    /// a time -- the next request isn't even read until the current one's reply has been sent.
+    pub fn run(self) -> impl Future<Output = Result<(), Error>> {
+        let Server { socket, mut handler } = self;
+        let (sink, stream) = socket.sink_stream().split();
+
+        stream.and_then(move |multipart| handler(multipart)).forward(sink)
+    }
+
+    /// Like `run`, but drives the socket from a dedicated background thread instead of
+    /// registering its file descriptor with a tokio reactor, buffering up to `capacity`
+    /// outstanding `Multipart`s in-memory -- the same `handler` closure runs unchanged either way,
+    /// since `SinkStreamSocket::threaded_sink_stream` and `sink_stream` hand back the same
+    /// `Sink`/`Stream` shape regardless of which `SocketBackend` drives them.
+    #[cfg(feature = "threaded-backend")]
+    pub fn run_threaded(self, capacity: usize) -> impl Future<Output = Result<(), Error>> {
+        let Server { socket, mut handler } = self;
+        let (sink, stream) = socket.threaded_sink_stream(capacity).split();
+
+        stream.and_then(move |multipart| handler(multipart)).forward(sink)
+    }
+
+    /// Like `run`, but drives up to `n` handler futures at once, forwarding each reply to the
+    /// sink as soon as it resolves rather than waiting for earlier requests to finish first.
+    ///
+    /// Replies may be sent out of request order; for a socket type that requires replies to come
+    /// back in order (e.g. `Rep`, which tracks one outstanding request at a time), use
+    /// `run_buffered` instead.
+    pub fn run_concurrent(self, n: usize) -> impl Future<Output = Result<(), Error>> {
+        let Server { socket, mut handler } = self;
+        let (sink, stream) = socket.sink_stream().split();
+
+        stream
+            .map(move |result| match result {
+                Ok(multipart) => Either::Left(handler(multipart)),
+                Err(e) => Either::Right(future::ready(Err(e))),
+            })
+            .buffer_unordered(n)
+            .forward(sink)
+    }
+
+    /// Like `run_concurrent`, but replies are forwarded in request order even though up to `n`
+    /// handler futures may be in flight at once.
+    pub fn run_buffered(self, n: usize) -> impl Future<Output = Result<(), Error>> {
+        let Server { socket, mut handler } = self;
+        let (sink, stream) = socket.sink_stream().split();
+
+        stream
+            .map(move |result| match result {
+                Ok(multipart) => Either::Left(handler(multipart)),
+                Err(e) => Either::Right(future::ready(Err(e))),
+            })
+            .buffered(n)
+            .forward(sink)
+    }
+
+    /// Like `run`, but for a ROUTER-style front socket: splits the `[identity...][empty]` routing
+    /// prefix off each incoming multipart before calling `handler` with just the payload, then
+    /// prepends the same prefix back onto the reply before forwarding it to the sink, so it routes
+    /// back to the peer that sent the request. Multiparts with no empty delimiter are treated as
+    /// payload with no identities; the reply to one still gets an empty delimiter frame prepended.
+    pub fn run_routed(self) -> impl Future<Output = Result<(), Error>> {
+        let Server { socket, mut handler } = self;
+        let (sink, stream) = socket.sink_stream().split();
+
+        stream
+            .and_then(move |multipart| {
+                let (identities, payload) = multipart.split_envelope();
+
+                handler(payload).map(move |result| {
+                    result.map(|reply| Multipart::with_envelope(identities, reply))
+                })
+            })
+            .forward(sink)
+    }
+
+    /// Like `run`, but a handler error doesn't end the service: `recover` is given the error and
+    /// can return `Some(reply)` to send a fallback reply instead, or `None` to drop that request
+    /// and keep polling the stream. The stream itself ending (e.g. the socket closing) still ends
+    /// the loop.
+    pub fn run_resilient<C>(self, mut recover: C) -> impl Future<Output = Result<(), Error>>
+    where
+        C: FnMut(Error) -> Option<Multipart>,
+    {
+        let Server { socket, mut handler } = self;
+        let (sink, stream) = socket.sink_stream().split();
+
+        stream
+            .then(move |result| match result {
+                Ok(multipart) => Either::Left(handler(multipart)),
+                Err(e) => Either::Right(future::ready(Err(e))),
+            })
+            .filter_map(move |result| {
+                future::ready(match result {
+                    Ok(multipart) => Some(Ok(multipart)),
+                    Err(e) => recover(e).map(Ok),
+                })
+            })
+            .forward(sink)
+    }
+
+    /// Wrap `handler` so each call is bounded by `duration`: if it hasn't resolved in time, the
+    /// request fails with `Error::Timeout` instead of stalling the rest of the pipeline forever.
+    ///
+    /// Combined with `run_resilient` this lets a service shed slow requests rather than let one
+    /// stuck handler call block every request behind it -- especially important for `Rep`, which
+    /// only ever has one outstanding request in flight at a time.
+    pub fn with_timeout(self, duration: Duration) -> Server<R, impl FnMut(Multipart) -> HandlerTimeout<F>>
+    where
+        F: Unpin,
+    {
+        let Server { socket, mut handler } = self;
+
+        Server {
+            socket,
+            handler: move |multipart| HandlerTimeout {
+                inner: handler(multipart),
+                timeout: Timer::default().sleep(duration),
+            },
+        }
+    }
+}
+
+/// The `Future` returned by wrapping a handler in `Server::with_timeout`.
+pub struct HandlerTimeout<F> {
+    inner: F,
+    timeout: Sleep,
+}
+
+impl<F> Future for HandlerTimeout<F>
+where
+    F: Future<Output = Result<Multipart, Error>> + Unpin,
+{
+    type Output = Result<Multipart, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        match Pin::new(&mut this.timeout).poll(cx) {
+            Poll::Ready(Ok(())) => return Poll::Ready(Err(Error::Timeout)),
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+            Poll::Pending => (),
+        }
+
+        Pin::new(&mut this.inner).poll(cx)
+    }
+}
+
+/// A synchronous request/reply handler: compute a reply multipart directly rather than building a
+/// `Future`.
+///
+/// `Server::new` already accepts a plain `FnMut(Multipart) -> impl Future<Output = Result<Multipart,
+/// Error>>` for handlers that need to do async work, so there's no separate async variant of this
+/// trait -- a closure already covers that case. `Responder` is for the simpler, fully synchronous
+/// case (an echo server, an in-memory lookup) where building a `Future` at all is unnecessary
+/// ceremony.
+pub trait Responder {
+    type Error: Into<Error>;
+
+    /// Compute the reply to `req`.
+    fn respond(&self, req: Multipart) -> Result<Multipart, Self::Error>;
+}
+
+/// Build a `Server` from a synchronous `Responder`, wrapping each call in `future::ready` so it
+/// fits the `FnMut(Multipart) -> impl Future<...>` shape `Server::new` expects.
+pub fn respond_with<R, T>(
+    socket: R,
+    responder: T,
+) -> Server<R, impl FnMut(Multipart) -> future::Ready<Result<Multipart, Error>>>
+where
+    R: StreamSocket + SinkSocket,
+    T: Responder,
+{
+    Server::new(socket, move |req| {
+        future::ready(responder.respond(req).map_err(Into::into))
+    })
+}
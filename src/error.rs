@@ -22,7 +22,9 @@ use std::fmt;
 use std::io::Error as IoError;
 
 use tokio_timer::TimerError;
-use zmq::Error as ZmqError;
+use zmq::{Error as ZmqError, SocketType};
+
+use message::Multipart;
 
 /// Defines the error type for Tokio ZMQ.
 ///
@@ -37,6 +39,19 @@ pub enum Error {
     Io(IoError),
     /// Stores Tokio Timer errors
     Timer(TimerError),
+    /// Returned when a `MultipartRequest`/`MultipartResponse` is polled after its inner socket
+    /// has already been handed back to the caller
+    Reused,
+    /// Returned when a `Proxy` is built from a frontend/backend pair of socket types that ZeroMQ
+    /// doesn't allow to be joined, e.g. a PUSH socket on both sides
+    IncompatibleSockets(SocketType, SocketType),
+    /// Returned when an operation bounded by a timeout doesn't finish in time -- a
+    /// `MultipartRequest`/`MultipartResponse` built with `with_timeout` whose socket doesn't
+    /// become ready, or a `Server::with_timeout`-wrapped handler call that doesn't resolve.
+    Timeout,
+    /// Returned when a send fails partway through a `Multipart`, carrying the frames queued after
+    /// the one that failed. The frame whose send failed is not included.
+    MultipartSend(Multipart, ZmqError),
 }
 
 impl From<ZmqError> for Error {
@@ -63,6 +78,14 @@ impl fmt::Display for Error {
             Error::Zmq(ref e) => write!(f, "Error from ZeroMQ: {}", e),
             Error::Io(ref e) => write!(f, "Error creating file descriptor: {}", e),
             Error::Timer(ref e) => write!(f, "Error creating timer: {}", e),
+            Error::Reused => write!(f, "Tried to poll a Future/Stream after completion"),
+            Error::IncompatibleSockets(front, back) => write!(
+                f,
+                "Cannot build a Proxy between {:?} and {:?} sockets",
+                front, back
+            ),
+            Error::Timeout => write!(f, "Timed out waiting for an operation to complete"),
+            Error::MultipartSend(_, ref e) => write!(f, "Failed partway through sending a multipart: {}", e),
         }
     }
 }
@@ -73,6 +96,20 @@ impl StdError for Error {
             Error::Zmq(_) => "Error interacting with ZeroMQ",
             Error::Io(_) => "Error building socket",
             Error::Timer(_) => "Error creating timed stream",
+            Error::Reused => "Tried to reuse a consumed Future/Stream",
+            Error::IncompatibleSockets(_, _) => "Tried to build a Proxy from incompatible socket types",
+            Error::Timeout => "Timed out waiting for an operation to complete",
+            Error::MultipartSend(_, _) => "Failed partway through sending a multipart",
+        }
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        match *self {
+            Error::Zmq(ref e) => Some(e),
+            Error::Io(ref e) => Some(e),
+            Error::Timer(ref e) => Some(e),
+            Error::MultipartSend(_, ref e) => Some(e),
+            Error::Reused | Error::IncompatibleSockets(_, _) | Error::Timeout => None,
         }
     }
 }
@@ -102,15 +102,26 @@ extern crate zmq;
 extern crate futures;
 extern crate tokio_core;
 extern crate tokio_file_unix;
+extern crate tokio_signal;
 #[macro_use]
 extern crate log;
 
 mod error;
 pub mod async;
+pub mod bridge;
+pub mod client;
+pub mod message;
+pub mod proxy;
+pub mod signal;
 pub mod socket;
 pub mod file;
 pub mod prelude;
 
+pub use self::bridge::{BackpressurePolicy, Bridge};
+pub use self::client::{Client, Server};
 pub use self::error::Error;
+pub use self::message::Multipart;
+pub use self::proxy::{LruBroker, Proxy};
+pub use self::signal::ShutdownOnCtrlC;
 pub use self::socket::Socket;
-pub use self::socket::{Dealer, Rep, Req, Router, Pub, Sub, Push, Pull, Xpub, Xsub, Pair};
+pub use self::socket::{Dealer, Rep, Req, Router, Pub, Sub, Push, Pull, Xpub, Xsub, Pair, Stream};
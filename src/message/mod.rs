@@ -22,6 +22,8 @@
 
 use std::collections::VecDeque;
 use std::collections::vec_deque::{IntoIter, Iter, IterMut};
+use std::iter::{Extend, FromIterator};
+use std::ops::{Deref, DerefMut};
 
 use zmq;
 
@@ -126,6 +128,30 @@ impl Multipart {
         self.inner.push_back(msg)
     }
 
+    /// Push a `&str` onto the back of the `Multipart` as a new frame, without requiring the
+    /// caller to build a `zmq::Message` themselves.
+    pub fn push_str(&mut self, msg: &str) {
+        self.push_bytes(msg.as_bytes());
+    }
+
+    /// Push a byte slice onto the back of the `Multipart` as a new frame.
+    pub fn push_bytes(&mut self, msg: &[u8]) {
+        let msg = zmq::Message::from_slice(msg).expect("Failed to allocate zmq::Message");
+        self.inner.push_back(msg);
+    }
+
+    /// Push a `&str` onto the front of the `Multipart` as a new frame, e.g. to prepend a filter
+    /// or address frame ahead of an already-built body.
+    pub fn push_front_str(&mut self, msg: &str) {
+        self.push_front_bytes(msg.as_bytes());
+    }
+
+    /// Push a byte slice onto the front of the `Multipart` as a new frame.
+    pub fn push_front_bytes(&mut self, msg: &[u8]) {
+        let msg = zmq::Message::from_slice(msg).expect("Failed to allocate zmq::Message");
+        self.inner.push_front(msg);
+    }
+
     pub fn is_empty(&self) -> bool {
         self.inner.is_empty()
     }
@@ -137,6 +163,64 @@ impl Multipart {
     pub fn iter_mut(&mut self) -> IterMut<zmq::Message> {
         self.inner.iter_mut()
     }
+
+    /// Peek at the frame a ROUTER socket prepends to an incoming `Multipart`, without consuming
+    /// it. Useful for a broker that needs to inspect the routing identity (e.g. to pick which
+    /// `Dealer` to forward to) before deciding whether to pop it off with `split_envelope`.
+    pub fn peek_identity(&self) -> Option<&zmq::Message> {
+        self.inner.front()
+    }
+
+    /// Whether `msg` is the empty delimiter frame ZeroMQ uses to separate the routing identity
+    /// stack from the payload in `[identity...][empty][payload...]` framing.
+    pub fn is_delimiter(msg: &zmq::Message) -> bool {
+        msg.is_empty()
+    }
+
+    /// Split the routing envelope off the front of the `Multipart`.
+    ///
+    /// This peels off every leading frame up to (and including) the first empty delimiter
+    /// frame, returning those identity frames separately from the remaining payload. This is the
+    /// `[identity...][empty][payload...]` framing ROUTER sockets produce.
+    pub fn split_envelope(mut self) -> (Vec<zmq::Message>, Multipart) {
+        let mut identities = Vec::new();
+
+        while let Some(frame) = self.pop_front() {
+            if frame.is_empty() {
+                return (identities, self);
+            }
+
+            identities.push(frame);
+        }
+
+        // No empty delimiter was found; treat the whole thing as payload with no identities.
+        (Vec::new(), Multipart { inner: identities.into() })
+    }
+
+    /// Rebuild a `[identity...][empty][payload...]` routing envelope from an identity stack and a
+    /// body, the inverse of `split_envelope`.
+    pub fn with_envelope(identities: Vec<zmq::Message>, body: Multipart) -> Multipart {
+        let mut multipart: Multipart = identities.into();
+
+        multipart.push_back(zmq::Message::from_slice(b"").expect("Failed to allocate zmq::Message"));
+        multipart.extend(body);
+
+        multipart
+    }
+}
+
+impl Deref for Multipart {
+    type Target = VecDeque<zmq::Message>;
+
+    fn deref(&self) -> &VecDeque<zmq::Message> {
+        &self.inner
+    }
+}
+
+impl DerefMut for Multipart {
+    fn deref_mut(&mut self) -> &mut VecDeque<zmq::Message> {
+        &mut self.inner
+    }
 }
 
 impl Default for Multipart {
@@ -155,9 +239,54 @@ impl From<zmq::Message> for Multipart {
     }
 }
 
-impl From<Vec<zmq::Message>> for Multipart {
-    fn from(v: Vec<zmq::Message>) -> Self {
-        Multipart { inner: v.into() }
+/// Build a single-frame `Multipart` directly out of an owned byte buffer, without the caller
+/// naming `zmq::Message::from_slice` themselves, e.g.
+/// `iter_ok(0..10).map(|i| format!("{}", i).into_bytes().into())` feeding a `MultipartSink`.
+impl From<Vec<u8>> for Multipart {
+    fn from(bytes: Vec<u8>) -> Self {
+        Multipart::from(zmq::Message::from(bytes))
+    }
+}
+
+/// Build a single-frame `Multipart` directly out of a borrowed byte slice.
+impl<'a> From<&'a [u8]> for Multipart {
+    fn from(bytes: &'a [u8]) -> Self {
+        Multipart::from(zmq::Message::from_slice(bytes).expect("Failed to allocate zmq::Message"))
+    }
+}
+
+/// Build a single-frame `Multipart` directly out of a borrowed string.
+impl<'a> From<&'a str> for Multipart {
+    fn from(s: &'a str) -> Self {
+        Multipart::from(s.as_bytes())
+    }
+}
+
+/// Build a `Multipart` out of a `Vec` of anything convertible into a `zmq::Message`, one frame per
+/// element, e.g. `vec![b"a".to_vec(), b"b".to_vec()].into()`. This subsumes the old
+/// `Vec<zmq::Message>`-only impl, since `zmq::Message: Into<zmq::Message>`.
+impl<T> From<Vec<T>> for Multipart
+where
+    T: Into<zmq::Message>,
+{
+    fn from(v: Vec<T>) -> Self {
+        Multipart {
+            inner: v.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl FromIterator<zmq::Message> for Multipart {
+    fn from_iter<I: IntoIterator<Item = zmq::Message>>(iter: I) -> Self {
+        Multipart {
+            inner: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl Extend<zmq::Message> for Multipart {
+    fn extend<I: IntoIterator<Item = zmq::Message>>(&mut self, iter: I) {
+        self.inner.extend(iter)
     }
 }
 
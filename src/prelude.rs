@@ -21,13 +21,16 @@
 
 use std::time::Duration;
 
-use futures_core::Stream;
+use futures_core::{Future, Stream};
 use tokio::reactor::PollEvented2;
 use tokio_file_unix::File;
 use zmq;
 
 use async::{ControlledStream, EndingStream, MultipartRequest, MultipartResponse, MultipartSink,
-            MultipartSinkStream, MultipartStream, TimeoutStream};
+            MultipartSinkStream, MultipartStream, SignalControlledStream, ThrottlingStream,
+            TimeoutStream};
+#[cfg(feature = "threaded-backend")]
+use async::ThreadedSocket;
 use error::Error;
 use file::ZmqFile;
 use message::Multipart;
@@ -35,8 +38,132 @@ use socket::Socket;
 
 /* ----------------------------------TYPES----------------------------------- */
 
+/// A parsed `[identity...][empty][payload...]` routing envelope, as produced and consumed by
+/// Router/Dealer chains.
+///
+/// Every Router/Dealer application ends up re-parsing this same framing: one or more identity
+/// frames (more than one after hopping through several Router/Dealer sockets), an empty delimiter
+/// frame, and the actual payload. `RoutingEnvelope` does that parsing once, so applications can
+/// work with the identity stack and payload separately instead of re-deriving the split by hand.
+///
+/// ### Example
+/// ```rust
+/// use tokio_zmq::prelude::RoutingEnvelope;
+/// use tokio_zmq::Multipart;
+///
+/// fn reply(request: Multipart, response: Multipart) -> Multipart {
+///     RoutingEnvelope::from_multipart(request)
+///         .with_payload(response)
+///         .into_multipart()
+/// }
+/// ```
+pub struct RoutingEnvelope {
+    identities: Vec<zmq::Message>,
+    payload: Multipart,
+}
+
+impl RoutingEnvelope {
+    /// Parse a `Multipart` received from a Router socket into its identity stack and payload.
+    ///
+    /// If no empty delimiter frame is found, the whole `Multipart` is treated as payload with an
+    /// empty identity stack.
+    pub fn from_multipart(multipart: Multipart) -> Self {
+        let (identities, payload) = multipart.split_envelope();
+
+        RoutingEnvelope { identities, payload }
+    }
+
+    /// Reassemble this envelope into a `Multipart` ready to hand back to a Router socket.
+    pub fn into_multipart(self) -> Multipart {
+        Multipart::with_envelope(self.identities, self.payload)
+    }
+
+    /// The identity stack, outermost hop first.
+    ///
+    /// This holds more than one frame when the envelope has passed through several Router/Dealer
+    /// hops; each hop prepends its own identity frame in front of the ones already present.
+    pub fn identities(&self) -> &[zmq::Message] {
+        &self.identities
+    }
+
+    /// The payload, with the routing prefix already stripped off.
+    pub fn payload(&self) -> &Multipart {
+        &self.payload
+    }
+
+    /// Consume this envelope, discarding the identity stack and returning only the payload.
+    pub fn into_payload(self) -> Multipart {
+        self.payload
+    }
+
+    /// Replace the payload, preserving the identity stack so the reply routes back the way the
+    /// request came.
+    pub fn with_payload(mut self, payload: Multipart) -> Self {
+        self.payload = payload;
+        self
+    }
+}
+
+/// A parsed XPUB subscribe/unsubscribe notification, as produced by `Xpub::subscription_stream`.
+///
+/// ZeroMQ delivers these as a single-frame message: one control byte (`1` for subscribe, `0` for
+/// unsubscribe) followed by the topic bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscriptionEvent {
+    pub subscribe: bool,
+    pub topic: Vec<u8>,
+}
+
+impl SubscriptionEvent {
+    /// Parse a single XPUB control frame, if it's well-formed (at least the one control byte).
+    pub(crate) fn from_message(msg: &zmq::Message) -> Option<Self> {
+        let bytes: &[u8] = msg;
+        let (&flag, topic) = bytes.split_first()?;
+
+        Some(SubscriptionEvent {
+            subscribe: flag == 1,
+            topic: topic.to_vec(),
+        })
+    }
+}
+
+/// Whether two ZeroMQ socket types can be wired directly together, per the canonical ZeroMQ
+/// socket compatibility table: PAIR only to PAIR; PUB/XPUB to SUB/XSUB and back; REQ to
+/// REP/ROUTER; REP to REQ/DEALER; DEALER to REP/DEALER/ROUTER; ROUTER to REQ/DEALER/ROUTER; and
+/// PUSH to PULL. STREAM sockets talk to raw TCP peers rather than other ZeroMQ sockets, so any
+/// pairing that includes one returns `false`.
+///
+/// Useful for validating a bind/connect pairing (e.g. in a `Controlled` socket's constructor,
+/// or up front in application code) before running the event loop, instead of discovering the
+/// mismatch as a silently dead connection at runtime.
+pub fn compatible(a: zmq::SocketType, b: zmq::SocketType) -> bool {
+    use zmq::SocketType::*;
+
+    match (a, b) {
+        (PAIR, PAIR) => true,
+        (PUB, SUB) | (PUB, XSUB) => true,
+        (SUB, PUB) | (SUB, XPUB) => true,
+        (XPUB, SUB) | (XPUB, XSUB) => true,
+        (XSUB, PUB) | (XSUB, XPUB) => true,
+        (REQ, REP) | (REQ, ROUTER) => true,
+        (REP, REQ) | (REP, DEALER) => true,
+        (DEALER, REP) | (DEALER, DEALER) | (DEALER, ROUTER) => true,
+        (ROUTER, REQ) | (ROUTER, DEALER) | (ROUTER, ROUTER) => true,
+        (PUSH, PULL) => true,
+        (PULL, PUSH) => true,
+        _ => false,
+    }
+}
+
 /* ----------------------------------TRAITS---------------------------------- */
 
+/// Implemented by every generated socket wrapper type, exposing the concrete `zmq::SocketType`
+/// it was built as. Combine with `compatible` to check a bind/connect pairing is sound.
+pub trait ZmqSocket {
+    /// The concrete ZeroMQ socket type this wrapper was built as.
+    fn socket_kind(&self) -> zmq::SocketType;
+}
+
 /// The `AsSocket` trait is implemented for all wrapper types. This makes implementing other traits a
 /// matter of saying a given type implements them.
 pub trait AsSocket: From<(zmq::Socket, PollEvented2<File<ZmqFile>>)> + Sized {
@@ -66,6 +193,23 @@ pub trait EndHandler {
     fn should_stop(&mut self, multipart: &Multipart) -> bool;
 }
 
+/// The `HeartbeatHandler` trait defines the ping/pong/liveness policy for a `HeartbeatStream`.
+pub trait HeartbeatHandler {
+    /// Build the `Multipart` to send as a ping; a `HeartbeatStream` calls this once per interval.
+    fn ping(&mut self) -> Multipart;
+
+    /// Whether an incoming `Multipart` is a pong, resetting the missed-beat counter.
+    ///
+    /// Non-pong traffic is still passed through to the stream's consumer; it's just not treated
+    /// as a sign of life on its own, matching ZMTP's dedicated PING/PONG commands rather than
+    /// resetting on any inbound data.
+    fn is_pong(&mut self, multipart: &Multipart) -> bool;
+
+    /// How many consecutive intervals may elapse without a pong before the peer is considered
+    /// dead and the stream ends.
+    fn max_missed_beats(&self) -> usize;
+}
+
 /// This trait provides the basic Stream support for ZeroMQ Sockets. It depends on `AsSocket`, but
 /// provides implementations for `sink` and `recv`.
 pub trait StreamSocket: AsSocket {
@@ -115,6 +259,11 @@ pub trait StreamSocket: AsSocket {
         self.socket().recv()
     }
 
+    /// Like `recv`, but fails with `Error::Timeout` if no frame arrives within `duration`.
+    fn recv_timeout(self, duration: Duration) -> MultipartResponse<Self> {
+        self.socket().recv_timeout(duration)
+    }
+
     /// Receive a stream of multipart messages from the socket.
     ///
     /// ### Example, using a Sub wrapper type
@@ -160,6 +309,18 @@ pub trait StreamSocket: AsSocket {
     fn stream(self) -> MultipartStream {
         self.socket().stream()
     }
+
+    /// Like `stream`, but drives the socket from a dedicated background thread instead of
+    /// registering its file descriptor with a tokio reactor.
+    ///
+    /// Every derived socket type gets this for free via the same `AsSocket` plumbing that powers
+    /// `stream`, so swapping backends doesn't require a different wrapper type -- just a
+    /// different constructor call.
+    #[cfg(feature = "threaded-backend")]
+    fn threaded_stream(self) -> MultipartStream<ThreadedSocket> {
+        let (sock, _file) = self.socket().inner();
+        MultipartStream::new_threaded(sock)
+    }
 }
 
 /// This trait provides the basic Sink support for ZeroMQ Sockets. It depends on `AsSocket` and
@@ -205,6 +366,22 @@ pub trait SinkSocket: AsSocket {
         self.socket().send(multipart)
     }
 
+    /// Like `send`, but fails with `Error::Timeout` if the send doesn't finish within `duration`.
+    fn send_timeout(self, multipart: Multipart, duration: Duration) -> MultipartRequest<Self> {
+        self.socket().send_timeout(multipart, duration)
+    }
+
+    /// Send frames convertible into `zmq::Message` (e.g. `&[u8]`, `Vec<u8>`) without materializing
+    /// a `zmq::Message` for any frame until it's actually sent, cutting an allocation+copy per
+    /// frame versus building a `Multipart` up front.
+    fn send_frames<S, I>(self, frames: I) -> MultipartRequest<Self, S>
+    where
+        S: Into<zmq::Message>,
+        I: IntoIterator<Item = S>,
+    {
+        self.socket().send_frames(frames)
+    }
+
     /// Send a stream of multipart messages to the socket.
     ///
     /// ### Example, using a Pub wrapper type
@@ -248,6 +425,23 @@ pub trait SinkSocket: AsSocket {
     fn sink(self) -> MultipartSink {
         self.socket().sink()
     }
+
+    /// Like `sink`, but buffers up to `capacity` outstanding `Multipart`s in-memory before
+    /// applying backpressure to the upstream producer, instead of waiting for each one to finish
+    /// sending before admitting the next. `capacity` of `0` is exactly `sink()` -- send
+    /// immediately, no extra buffering.
+    fn sink_with_capacity(self, capacity: usize) -> MultipartSink {
+        self.socket().sink_with_capacity(capacity)
+    }
+
+    /// Like `sink`, but drives the socket from a dedicated background thread instead of
+    /// registering its file descriptor with a tokio reactor, buffering up to `capacity`
+    /// outstanding `Multipart`s in-memory before applying backpressure.
+    #[cfg(feature = "threaded-backend")]
+    fn threaded_sink(self, capacity: usize) -> MultipartSink<ThreadedSocket> {
+        let (sock, _file) = self.socket().inner();
+        MultipartSink::with_threaded_backend(sock, capacity)
+    }
 }
 
 /// This trait is provided for sockets that implement both Sync and Stream
@@ -287,10 +481,21 @@ pub trait SinkStreamSocket: AsSocket {
     /// }
     /// ```
     fn sink_stream(self) -> MultipartSinkStream;
+
+    /// Like `sink_stream`, but buffers up to `capacity` outstanding `Multipart`s in-memory before
+    /// applying backpressure to the upstream producer, so a stream forwarded into the sink half
+    /// doesn't stall waiting for each message to finish sending.
+    fn sink_stream_with_capacity(self, capacity: usize) -> MultipartSinkStream;
+
+    /// Like `sink_stream`, but drives the socket from a dedicated background thread instead of
+    /// registering its file descriptor with a tokio reactor, buffering up to `capacity`
+    /// outstanding `Multipart`s in-memory before applying backpressure.
+    #[cfg(feature = "threaded-backend")]
+    fn threaded_sink_stream(self, capacity: usize) -> MultipartSinkStream<ThreadedSocket>;
 }
 
 /// This trait is provided to allow for ending a stream based on a Multipart message it receives.
-pub trait WithEndHandler: Stream<Item = Multipart, Error = Error> + Sized {
+pub trait WithEndHandler: Stream<Item = Result<Multipart, Error>> + Unpin + Sized {
     /// Add an EndHandler to a stream.
     ///
     /// ### Example, using a Sub wrapper type
@@ -339,9 +544,9 @@ pub trait WithEndHandler: Stream<Item = Multipart, Error = Error> + Sized {
         E: EndHandler;
 }
 
-/// This trait is implemented by all Streams with Item = Multipart and Error = Error, it provides
+/// This trait is implemented by all Streams with Item = Result<Multipart, Error>, it provides
 /// the ability to control when the stream stops based on the content of another stream.
-pub trait Controllable: Stream<Item = Multipart, Error = Error> + Sized {
+pub trait Controllable: Stream<Item = Result<Multipart, Error>> + Unpin + Sized {
     /// Add a controller stream to a given stream. This allows the controller stream to decide when
     /// the controlled stream should stop.
     ///
@@ -392,11 +597,51 @@ pub trait Controllable: Stream<Item = Multipart, Error = Error> + Sized {
     fn controlled<H, S>(self, control_stream: S, handler: H) -> ControlledStream<H, S, Self>
     where
         H: ControlHandler,
-        S: Stream<Item = Multipart, Error = Error>;
+        S: Stream<Item = Result<Multipart, Error>> + Unpin;
 }
 
-/// This trait allows adding a timeout to any stream with Error = Error.
-pub trait WithTimeout: Stream<Error = Error> + Sized {
+/// This trait is implemented by all Streams with Item = Result<Multipart, Error>, it provides
+/// the ability to stop a stream once a signal future (such as `ShutdownOnCtrlC`) resolves.
+pub trait WithSignalControl: Stream<Item = Result<Multipart, Error>> + Unpin + Sized {
+    /// Add a signal future to a given stream. Once the signal resolves, the stream stops.
+    ///
+    /// ### Example, using a controlled Pull wrapper type and `ShutdownOnCtrlC`
+    /// ```rust
+    /// #![feature(try_from)]
+    ///
+    /// extern crate futures_util;
+    /// extern crate tokio_zmq;
+    /// extern crate zmq;
+    ///
+    /// use std::convert::TryInto;
+    /// use std::sync::Arc;
+    ///
+    /// use futures_util::{FutureExt, StreamExt};
+    /// use tokio_zmq::prelude::*;
+    /// use tokio_zmq::{Socket, Pull, ShutdownOnCtrlC};
+    ///
+    /// fn main() {
+    ///     let ctx = Arc::new(zmq::Context::new());
+    ///     let pull: Pull = Socket::builder(ctx)
+    ///         .bind("tcp://*:5575")
+    ///         .try_into()
+    ///         .unwrap();
+    ///
+    ///     let fut = pull.stream().controlled_by_signal(ShutdownOnCtrlC::new());
+    ///
+    ///     // tokio::reactor::run2(fut.map(|_| ()).or_else(|e| {
+    ///     //     println!("Error: {}", e);
+    ///     //     Ok(())
+    ///     // }));
+    /// }
+    /// ```
+    fn controlled_by_signal<F>(self, signal: F) -> SignalControlledStream<F, Self>
+    where
+        F: Future<Output = Result<(), Error>> + Unpin;
+}
+
+/// This trait allows adding a timeout to any stream yielding `Result<T, Error>`.
+pub trait WithTimeout: Stream + Unpin + Sized {
     /// Add a timeout to a given stream.
     ///
     /// ### Example, using a Pull wrapper type
@@ -434,6 +679,47 @@ pub trait WithTimeout: Stream<Error = Error> + Sized {
     fn timeout(self, duration: Duration) -> TimeoutStream<Self>;
 }
 
+/// This trait allows throttling any stream yielding `Result<T, Error>`, coalescing polls under
+/// high message rates.
+pub trait WithThrottle: Stream + Unpin + Sized {
+    /// Throttle a given stream, draining up to `max_batch` items from it at most once per
+    /// `duration`.
+    ///
+    /// ### Example, using a Pull wrapper type
+    /// ```rust
+    /// #![feature(try_from)]
+    ///
+    /// extern crate futures_util;
+    /// extern crate tokio_zmq;
+    /// extern crate zmq;
+    ///
+    /// use std::convert::TryInto;
+    /// use std::sync::Arc;
+    /// use std::time::Duration;
+    ///
+    /// use futures_util::{FutureExt, StreamExt};
+    /// use tokio_zmq::prelude::*;
+    /// use tokio_zmq::{Socket, Pull, Multipart};
+    ///
+    /// fn main() {
+    ///     let ctx = Arc::new(zmq::Context::new());
+    ///     let pull: Pull = Socket::builder(ctx)
+    ///         .bind("tcp://*:5576")
+    ///         .try_into()
+    ///         .unwrap();
+    ///
+    ///     // Poll the socket at most once every 10ms, in batches of up to 100 messages
+    ///     let fut = pull.stream().throttled(Duration::from_millis(10), 100);
+    ///
+    ///     // tokio::reactor::run2(fut.map(|_| ()).or_else(|e| {
+    ///     //     println!("Error: {}", e);
+    ///     //     Ok(())
+    ///     // }));
+    /// }
+    /// ```
+    fn throttled(self, duration: Duration, max_batch: usize) -> ThrottlingStream<Self>;
+}
+
 /* ----------------------------------impls----------------------------------- */
 
 impl<T> SinkStreamSocket for T
@@ -443,11 +729,21 @@ where
     fn sink_stream(self) -> MultipartSinkStream {
         self.socket().sink_stream()
     }
+
+    fn sink_stream_with_capacity(self, capacity: usize) -> MultipartSinkStream {
+        self.socket().sink_stream_with_capacity(capacity)
+    }
+
+    #[cfg(feature = "threaded-backend")]
+    fn threaded_sink_stream(self, capacity: usize) -> MultipartSinkStream<ThreadedSocket> {
+        let (sock, _file) = self.socket().inner();
+        MultipartSinkStream::with_threaded_backend(sock, capacity)
+    }
 }
 
 impl<T> WithEndHandler for T
 where
-    T: Stream<Item = Multipart, Error = Error>,
+    T: Stream<Item = Result<Multipart, Error>> + Unpin,
 {
     fn with_end_handler<E>(self, end_handler: E) -> EndingStream<E, Self>
     where
@@ -459,22 +755,43 @@ where
 
 impl<T> Controllable for T
 where
-    T: Stream<Item = Multipart, Error = Error>,
+    T: Stream<Item = Result<Multipart, Error>> + Unpin,
 {
     fn controlled<H, S>(self, control_stream: S, handler: H) -> ControlledStream<H, S, Self>
     where
         H: ControlHandler,
-        S: Stream<Item = Multipart, Error = Error>,
+        S: Stream<Item = Result<Multipart, Error>> + Unpin,
     {
         ControlledStream::new(self, control_stream, handler)
     }
 }
 
+impl<T> WithSignalControl for T
+where
+    T: Stream<Item = Result<Multipart, Error>> + Unpin,
+{
+    fn controlled_by_signal<F>(self, signal: F) -> SignalControlledStream<F, Self>
+    where
+        F: Future<Output = Result<(), Error>> + Unpin,
+    {
+        SignalControlledStream::new(self, signal)
+    }
+}
+
 impl<T> WithTimeout for T
 where
-    T: Stream<Error = Error>,
+    T: Stream + Unpin,
 {
     fn timeout(self, duration: Duration) -> TimeoutStream<Self> {
         TimeoutStream::new(self, duration)
     }
 }
+
+impl<T> WithThrottle for T
+where
+    T: Stream + Unpin,
+{
+    fn throttled(self, duration: Duration, max_batch: usize) -> ThrottlingStream<Self> {
+        ThrottlingStream::new(self, duration, max_batch)
+    }
+}
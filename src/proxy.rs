@@ -0,0 +1,520 @@
+/*
+ * This file is part of Tokio ZMQ.
+ *
+ * Copyright © 2017 Riley Trautman
+ *
+ * Tokio ZMQ is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Tokio ZMQ is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Tokio ZMQ.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! This module defines the `Proxy` type, a future that shuttles `Multipart`s between a frontend
+//! and a backend socket, similar to `zmq_proxy`/`zmq_proxy_steerable`. It also defines
+//! `LruBroker`, a proxy variant that tracks worker readiness the way the ZeroMQ guide's
+//! load-balancing broker pattern does.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use zmq;
+use futures::{Future, Sink, Stream};
+
+use async::MultipartSinkStream;
+use async::sink::MultipartSink;
+use error::Error;
+use message::Multipart;
+use prelude::{ControlHandler, RoutingEnvelope};
+
+// The 11 socket types a `Proxy` can be built from, in the order the compatibility matrix below
+// indexes them by.
+const SOCKET_TYPES: [zmq::SocketType; 11] = [
+    zmq::PAIR,
+    zmq::PUB,
+    zmq::SUB,
+    zmq::REQ,
+    zmq::REP,
+    zmq::DEALER,
+    zmq::ROUTER,
+    zmq::PULL,
+    zmq::PUSH,
+    zmq::XPUB,
+    zmq::XSUB,
+];
+
+// COMPATIBLE[i][j] is true when a frontend of SOCKET_TYPES[i] may be proxied to a backend of
+// SOCKET_TYPES[j]: PAIR-PAIR, PUB/XPUB to SUB/XSUB, REQ/REP/DEALER/ROUTER's various request-reply
+// combinations, and PUSH-PULL.
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const COMPATIBLE: [[bool; 11]; 11] = [
+    //       PAIR   PUB    SUB    REQ    REP    DEALER ROUTER PULL   PUSH   XPUB   XSUB
+    /* PAIR */   [true,  false, false, false, false, false, false, false, false, false, false],
+    /* PUB */    [false, false, true,  false, false, false, false, false, false, false, true ],
+    /* SUB */    [false, true,  false, false, false, false, false, false, false, true,  false],
+    /* REQ */    [false, false, false, false, true,  false, true,  false, false, false, false],
+    /* REP */    [false, false, false, true,  false, true,  false, false, false, false, false],
+    /* DEALER */ [false, false, false, false, true,  true,  true,  false, false, false, false],
+    /* ROUTER */ [false, false, false, true,  false, true,  true,  false, false, false, false],
+    /* PULL */   [false, false, false, false, false, false, false, false, true,  false, false],
+    /* PUSH */   [false, false, false, false, false, false, false, true,  false, false, false],
+    /* XPUB */   [false, false, true,  false, false, false, false, false, false, false, true ],
+    /* XSUB */   [false, true,  false, false, false, false, false, false, false, true,  false],
+];
+
+fn socket_index(ty: zmq::SocketType) -> Option<usize> {
+    SOCKET_TYPES.iter().position(|&t| t == ty)
+}
+
+/// Check whether ZeroMQ allows a `front`/`back` socket-type pairing to be joined into a device,
+/// e.g. `compatible(zmq::ROUTER, zmq::DEALER)`.
+pub fn compatible(front: zmq::SocketType, back: zmq::SocketType) -> bool {
+    match (socket_index(front), socket_index(back)) {
+        (Some(f), Some(b)) => COMPATIBLE[f][b],
+        _ => false,
+    }
+}
+
+fn check_compatible(front: zmq::SocketType, back: zmq::SocketType) -> Result<(), Error> {
+    if compatible(front, back) {
+        Ok(())
+    } else {
+        Err(Error::IncompatibleSockets(front, back))
+    }
+}
+
+fn capture(sink: &mut Option<MultipartSink>, multipart: &Multipart, cx: &mut Context) -> Result<(), Error> {
+    if let Some(capture) = sink.as_mut() {
+        let mut mirror = Multipart::new();
+        for msg in multipart {
+            mirror.push_back(zmq::Message::from_slice(&*msg)?);
+        }
+
+        Pin::new(&mut *capture).start_send(mirror)?;
+        if let Poll::Ready(Err(e)) = Pin::new(&mut *capture).poll_flush(cx) {
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// A bidirectional relay between a frontend and a backend socket.
+///
+/// `Proxy` polls both directions in an alternating fashion so that a busy frontend can't starve
+/// the backend (or vice versa), forwarding everything it receives on one side to the other. An
+/// optional capture sink receives a copy of every `Multipart` that passes through, in either
+/// direction, and an optional control stream can stop the proxy the same way `ControlledStream`
+/// does.
+///
+/// This is the programmatic equivalent of the `streamer`, `forwarder`, and `queue` devices
+/// ZeroMQ ships, e.g. `Proxy::new(zmq::ROUTER, router, zmq::DEALER, dealer)` for a `queue` device.
+pub struct Proxy<C = NoControl, H = NoControl> {
+    front: MultipartSinkStream,
+    back: MultipartSinkStream,
+    capture: Option<MultipartSink>,
+    control: Option<(C, H)>,
+    front_turn: bool,
+}
+
+/// A placeholder control stream type used when a `Proxy` has no control source. Users never
+/// construct this directly; it only exists to give `Proxy`'s default type parameters something
+/// to name.
+pub struct NoControl;
+
+impl ControlHandler for NoControl {
+    fn should_stop(&mut self, _: Multipart) -> bool {
+        false
+    }
+}
+
+impl Stream for NoControl {
+    type Item = Result<Multipart, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, _: &mut Context) -> Poll<Option<Self::Item>> {
+        Poll::Pending
+    }
+}
+
+impl Proxy<NoControl, NoControl> {
+    /// Create a new `Proxy` relaying between `front` and `back`.
+    ///
+    /// `front_type` and `back_type` are the `zmq::SocketType`s `front` and `back` were built
+    /// with; they're checked against ZeroMQ's device compatibility rules (see `compatible`)
+    /// before the `Proxy` is constructed, so a mismatched pairing like two PUSH sockets fails
+    /// here instead of silently dropping every `Multipart` at runtime.
+    pub fn new(
+        front_type: zmq::SocketType,
+        front: MultipartSinkStream,
+        back_type: zmq::SocketType,
+        back: MultipartSinkStream,
+    ) -> Result<Self, Error> {
+        check_compatible(front_type, back_type)?;
+
+        Ok(Proxy {
+            front,
+            back,
+            capture: None,
+            control: None,
+            front_turn: true,
+        })
+    }
+
+    /// Allow `control` to stop the proxy early, using `handler` to decide when to stop.
+    pub fn controlled<C, H>(self, control: C, handler: H) -> Proxy<C, H>
+    where
+        C: Stream<Item = Result<Multipart, Error>> + Unpin,
+        H: ControlHandler,
+    {
+        Proxy {
+            front: self.front,
+            back: self.back,
+            capture: self.capture,
+            control: Some((control, handler)),
+            front_turn: self.front_turn,
+        }
+    }
+}
+
+impl<C, H> Proxy<C, H> {
+    /// Mirror every `Multipart` that passes through the proxy, in both directions, to `capture`.
+    pub fn capture(mut self, capture: MultipartSink) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+}
+
+impl<C, H> Proxy<C, H>
+where
+    C: Stream<Item = Result<Multipart, Error>> + Unpin,
+    H: ControlHandler,
+{
+    fn poll_control(&mut self, cx: &mut Context) -> Result<bool, Error> {
+        let stop = match self.control {
+            Some((ref mut control, ref mut handler)) => match Pin::new(control).poll_next(cx) {
+                Poll::Ready(None) => true,
+                Poll::Ready(Some(Ok(multipart))) => handler.should_stop(multipart),
+                Poll::Ready(Some(Err(e))) => return Err(e),
+                Poll::Pending => false,
+            },
+            None => false,
+        };
+
+        Ok(stop)
+    }
+
+    fn relay(
+        from: &mut MultipartSinkStream,
+        to: &mut MultipartSinkStream,
+        capture_sink: &mut Option<MultipartSink>,
+        cx: &mut Context,
+    ) -> Result<bool, Error> {
+        // Don't even pull the next Multipart off `from` until `to` can actually accept it --
+        // otherwise a `from` that already has several queued (e.g. a busy ROUTER frontend) would
+        // have us call `start_send` on `to` again before its previous frame finished flushing,
+        // overwriting it.
+        match Pin::new(&mut *to).poll_ready(cx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(e)) => return Err(e),
+            Poll::Pending => return Ok(false),
+        }
+
+        match Pin::new(&mut *from).poll_next(cx) {
+            Poll::Ready(Some(Ok(multipart))) => {
+                capture(capture_sink, &multipart, cx)?;
+                Pin::new(&mut *to).start_send(multipart)?;
+                if let Poll::Ready(Err(e)) = Pin::new(&mut *to).poll_flush(cx) {
+                    return Err(e);
+                }
+                Ok(true)
+            }
+            Poll::Ready(Some(Err(e))) => Err(e),
+            Poll::Ready(None) => Ok(false),
+            Poll::Pending => Ok(false),
+        }
+    }
+}
+
+impl<C, H> Future for Proxy<C, H>
+where
+    C: Stream<Item = Result<Multipart, Error>> + Unpin,
+    H: ControlHandler,
+{
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            match this.poll_control(cx) {
+                Ok(true) => {
+                    if let Poll::Ready(Err(e)) = Pin::new(&mut this.front).poll_close(cx) {
+                        return Poll::Ready(Err(e));
+                    }
+                    if let Poll::Ready(Err(e)) = Pin::new(&mut this.back).poll_close(cx) {
+                        return Poll::Ready(Err(e));
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(false) => (),
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+
+            // Alternate which direction goes first each time through, so neither side can
+            // starve the other under sustained load.
+            this.front_turn = !this.front_turn;
+
+            let (made_progress_1, made_progress_2) = if this.front_turn {
+                let a = match Self::relay(&mut this.front, &mut this.back, &mut this.capture, cx) {
+                    Ok(progress) => progress,
+                    Err(e) => return Poll::Ready(Err(e)),
+                };
+                let b = match Self::relay(&mut this.back, &mut this.front, &mut this.capture, cx) {
+                    Ok(progress) => progress,
+                    Err(e) => return Poll::Ready(Err(e)),
+                };
+                (a, b)
+            } else {
+                let a = match Self::relay(&mut this.back, &mut this.front, &mut this.capture, cx) {
+                    Ok(progress) => progress,
+                    Err(e) => return Poll::Ready(Err(e)),
+                };
+                let b = match Self::relay(&mut this.front, &mut this.back, &mut this.capture, cx) {
+                    Ok(progress) => progress,
+                    Err(e) => return Poll::Ready(Err(e)),
+                };
+                (a, b)
+            };
+
+            if !made_progress_1 && !made_progress_2 {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+const READY: &'static str = "READY";
+
+/// A broker that only dispatches client requests to idle workers, the "Least Recently Used"
+/// queue pattern from the ZeroMQ guide's load-balancing broker.
+///
+/// `front` is a Router facing clients, `back` is a Router facing workers. A worker announces
+/// itself as idle by sending a single `READY` frame; `LruBroker` holds onto its identity until a
+/// client request is available, dispatches `[client-envelope]` to that worker, and requeues the
+/// worker's identity as soon as its reply comes back through.
+pub struct LruBroker<C = NoControl, H = NoControl> {
+    front: MultipartSinkStream,
+    back: MultipartSinkStream,
+    capture: Option<MultipartSink>,
+    control: Option<(C, H)>,
+    ready_workers: VecDeque<Vec<zmq::Message>>,
+    pending_requests: VecDeque<Multipart>,
+    pending_replies: VecDeque<Multipart>,
+}
+
+impl LruBroker<NoControl, NoControl> {
+    /// Create a new `LruBroker` relaying client requests on `front` to idle workers on `back`.
+    pub fn new(front: MultipartSinkStream, back: MultipartSinkStream) -> Self {
+        LruBroker {
+            front,
+            back,
+            capture: None,
+            control: None,
+            ready_workers: VecDeque::new(),
+            pending_requests: VecDeque::new(),
+            pending_replies: VecDeque::new(),
+        }
+    }
+
+    /// Allow `control` to stop the broker early, using `handler` to decide when to stop.
+    pub fn controlled<C, H>(self, control: C, handler: H) -> LruBroker<C, H>
+    where
+        C: Stream<Item = Result<Multipart, Error>> + Unpin,
+        H: ControlHandler,
+    {
+        LruBroker {
+            front: self.front,
+            back: self.back,
+            capture: self.capture,
+            control: Some((control, handler)),
+            ready_workers: self.ready_workers,
+            pending_requests: self.pending_requests,
+            pending_replies: self.pending_replies,
+        }
+    }
+}
+
+impl<C, H> LruBroker<C, H> {
+    /// Mirror every `Multipart` that passes through the broker, in both directions, to `capture`.
+    pub fn capture(mut self, capture: MultipartSink) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+}
+
+impl<C, H> LruBroker<C, H>
+where
+    C: Stream<Item = Result<Multipart, Error>> + Unpin,
+    H: ControlHandler,
+{
+    fn poll_control(&mut self, cx: &mut Context) -> Result<bool, Error> {
+        let stop = match self.control {
+            Some((ref mut control, ref mut handler)) => match Pin::new(control).poll_next(cx) {
+                Poll::Ready(None) => true,
+                Poll::Ready(Some(Ok(multipart))) => handler.should_stop(multipart),
+                Poll::Ready(Some(Err(e))) => return Err(e),
+                Poll::Pending => false,
+            },
+            None => false,
+        };
+
+        Ok(stop)
+    }
+
+    /// Send as many queued client requests as there are idle workers to handle them.
+    fn dispatch(&mut self, cx: &mut Context) -> Result<(), Error> {
+        while !self.ready_workers.is_empty() && !self.pending_requests.is_empty() {
+            match Pin::new(&mut self.back).poll_ready(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Err(e),
+                Poll::Pending => break,
+            }
+
+            let worker = self.ready_workers
+                .pop_front()
+                .expect("ready_workers was just checked to be non-empty");
+            let request = self.pending_requests
+                .pop_front()
+                .expect("pending_requests was just checked to be non-empty");
+
+            let mut dispatch: Multipart = worker.into();
+            dispatch.push_back(zmq::Message::from_slice(b"").expect("Failed to allocate zmq::Message"));
+            dispatch.extend(request);
+
+            capture(&mut self.capture, &dispatch, cx)?;
+            Pin::new(&mut self.back).start_send(dispatch)?;
+            if let Poll::Ready(Err(e)) = Pin::new(&mut self.back).poll_flush(cx) {
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Send as many queued worker replies back to the client-facing front as it can accept.
+    fn deliver_replies(&mut self, cx: &mut Context) -> Result<(), Error> {
+        while !self.pending_replies.is_empty() {
+            match Pin::new(&mut self.front).poll_ready(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Err(e),
+                Poll::Pending => break,
+            }
+
+            let payload = self.pending_replies
+                .pop_front()
+                .expect("pending_replies was just checked to be non-empty");
+
+            capture(&mut self.capture, &payload, cx)?;
+            Pin::new(&mut self.front).start_send(payload)?;
+            if let Poll::Ready(Err(e)) = Pin::new(&mut self.front).poll_flush(cx) {
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn poll_front(&mut self, cx: &mut Context) -> Result<bool, Error> {
+        match Pin::new(&mut self.front).poll_next(cx) {
+            Poll::Ready(Some(Ok(multipart))) => {
+                self.pending_requests.push_back(multipart);
+                Ok(true)
+            }
+            Poll::Ready(Some(Err(e))) => Err(e),
+            Poll::Ready(None) => Ok(false),
+            Poll::Pending => Ok(false),
+        }
+    }
+
+    fn poll_back(&mut self, cx: &mut Context) -> Result<bool, Error> {
+        match Pin::new(&mut self.back).poll_next(cx) {
+            Poll::Ready(Some(Ok(multipart))) => {
+                let envelope = RoutingEnvelope::from_multipart(multipart);
+                let worker = envelope.identities().to_vec();
+                let payload = envelope.into_payload();
+
+                let is_ready = payload
+                    .get(0)
+                    .map(|msg| msg.as_str() == Some(READY))
+                    .unwrap_or(false);
+
+                if !is_ready {
+                    self.pending_replies.push_back(payload);
+                }
+
+                self.ready_workers.push_back(worker);
+
+                Ok(true)
+            }
+            Poll::Ready(Some(Err(e))) => Err(e),
+            Poll::Ready(None) => Ok(false),
+            Poll::Pending => Ok(false),
+        }
+    }
+}
+
+impl<C, H> Future for LruBroker<C, H>
+where
+    C: Stream<Item = Result<Multipart, Error>> + Unpin,
+    H: ControlHandler,
+{
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            match this.poll_control(cx) {
+                Ok(true) => {
+                    if let Poll::Ready(Err(e)) = Pin::new(&mut this.front).poll_close(cx) {
+                        return Poll::Ready(Err(e));
+                    }
+                    if let Poll::Ready(Err(e)) = Pin::new(&mut this.back).poll_close(cx) {
+                        return Poll::Ready(Err(e));
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(false) => (),
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+
+            let front_progress = match this.poll_front(cx) {
+                Ok(progress) => progress,
+                Err(e) => return Poll::Ready(Err(e)),
+            };
+            let back_progress = match this.poll_back(cx) {
+                Ok(progress) => progress,
+                Err(e) => return Poll::Ready(Err(e)),
+            };
+
+            if let Err(e) = this.dispatch(cx) {
+                return Poll::Ready(Err(e));
+            }
+            if let Err(e) = this.deliver_replies(cx) {
+                return Poll::Ready(Err(e));
+            }
+
+            if !front_progress && !back_progress {
+                return Poll::Pending;
+            }
+        }
+    }
+}
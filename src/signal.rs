@@ -0,0 +1,71 @@
+/*
+ * This file is part of Tokio ZMQ.
+ *
+ * Copyright © 2017 Riley Trautman
+ *
+ * Tokio ZMQ is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * Tokio ZMQ is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Tokio ZMQ.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+//! This module provides ready-made OS-signal control sources for use with
+//! `WithSignalControl::controlled_by_signal`.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::{Future, Stream};
+use tokio_signal;
+
+use error::Error;
+
+/// A ready-made control source that resolves once the process receives an interrupt (ctrl-c).
+///
+/// Pair this with `controlled_by_signal` to let a standalone service shut down cleanly without
+/// standing up a dedicated Pub/Sub control channel just to signal itself.
+pub struct ShutdownOnCtrlC {
+    inner: tokio_signal::IoFuture<tokio_signal::CtrlC>,
+    signal: Option<tokio_signal::CtrlC>,
+}
+
+impl ShutdownOnCtrlC {
+    /// Begin listening for ctrl-c.
+    pub fn new() -> Self {
+        ShutdownOnCtrlC {
+            inner: tokio_signal::CtrlC::new(),
+            signal: None,
+        }
+    }
+}
+
+impl Future for ShutdownOnCtrlC {
+    type Output = Result<(), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(signal) = this.signal.as_mut() {
+                return match Pin::new(signal).poll_next(cx) {
+                    Poll::Ready(_) => Poll::Ready(Ok(())),
+                    Poll::Pending => Poll::Pending,
+                };
+            }
+
+            match Pin::new(&mut this.inner).poll(cx) {
+                Poll::Ready(Ok(signal)) => this.signal = Some(signal),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(Error::Io(e))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -20,6 +20,7 @@
 //! This module contains `SocketBuilder` and related types.
 
 use std::rc::Rc;
+use std::sync::Arc;
 
 use zmq;
 use tokio::reactor::PollEvented2;
@@ -29,6 +30,73 @@ use socket::Socket;
 use error::Error;
 use file::ZmqFile;
 
+/// A single deferred ZeroMQ socket option, applied during `build()` after the socket is created
+/// but before `bind`/`connect`.
+///
+/// Kept as an enum (rather than calling `set_*` immediately) so the option list can be built up
+/// across several builder calls and replayed in the correct order once the underlying
+/// `zmq::Socket` actually exists.
+#[derive(Clone)]
+pub(crate) enum SockOpt<'a> {
+    Sndhwm(i32),
+    Rcvhwm(i32),
+    Linger(i32),
+    ReconnectIvl(i32),
+    TcpKeepalive(i32),
+    Rcvtimeo(i32),
+    Sndtimeo(i32),
+    Raw(Rc<Fn(&zmq::Socket) -> zmq::Result<()>>),
+    CurveServer(&'a [u8]),
+    CurveClient {
+        server_key: &'a [u8],
+        public_key: &'a [u8],
+        secret_key: &'a [u8],
+    },
+    PlainServer,
+    PlainClient { username: &'a str, password: &'a str },
+    Heartbeat { ivl: i32, timeout: i32, ttl: i32 },
+}
+
+fn apply_options<'a>(sock: &zmq::Socket, options: &[SockOpt<'a>]) -> zmq::Result<()> {
+    for option in options {
+        match *option {
+            SockOpt::Sndhwm(v) => sock.set_sndhwm(v)?,
+            SockOpt::Rcvhwm(v) => sock.set_rcvhwm(v)?,
+            SockOpt::Linger(v) => sock.set_linger(v)?,
+            SockOpt::ReconnectIvl(v) => sock.set_reconnect_ivl(v)?,
+            SockOpt::TcpKeepalive(v) => sock.set_tcp_keepalive(v)?,
+            SockOpt::Rcvtimeo(v) => sock.set_rcvtimeo(v)?,
+            SockOpt::Sndtimeo(v) => sock.set_sndtimeo(v)?,
+            SockOpt::Raw(ref f) => f(sock)?,
+            SockOpt::CurveServer(secret_key) => {
+                sock.set_curve_server(true)?;
+                sock.set_curve_secretkey(secret_key)?;
+            }
+            SockOpt::CurveClient {
+                server_key,
+                public_key,
+                secret_key,
+            } => {
+                sock.set_curve_serverkey(server_key)?;
+                sock.set_curve_publickey(public_key)?;
+                sock.set_curve_secretkey(secret_key)?;
+            }
+            SockOpt::PlainServer => sock.set_plain_server(true)?,
+            SockOpt::PlainClient { username, password } => {
+                sock.set_plain_username(username)?;
+                sock.set_plain_password(password)?;
+            }
+            SockOpt::Heartbeat { ivl, timeout, ttl } => {
+                sock.set_heartbeat_ivl(ivl)?;
+                sock.set_heartbeat_timeout(timeout)?;
+                sock.set_heartbeat_ttl(ttl)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
 fn bind_all(sock: zmq::Socket, binds: &[&str]) -> zmq::Result<zmq::Socket> {
     for bind in binds {
         sock.bind(bind)?;
@@ -47,8 +115,9 @@ fn connect_all(sock: zmq::Socket, connects: &[&str]) -> zmq::Result<zmq::Socket>
 ///
 /// This struct contains a context and an identity.
 pub struct SocketBuilder<'a> {
-    ctx: Rc<zmq::Context>,
+    ctx: Arc<zmq::Context>,
     identity: Option<&'a [u8]>,
+    options: Vec<SockOpt<'a>>,
 }
 
 impl<'a> SocketBuilder<'a> {
@@ -56,21 +125,116 @@ impl<'a> SocketBuilder<'a> {
     ///
     /// All sockets that are created through the Tokio ZMQ library will use this as the base for
     /// their socket builder (except PAIR sockets).
-    pub fn new(ctx: Rc<zmq::Context>) -> Self {
+    pub fn new(ctx: Arc<zmq::Context>) -> Self {
         SocketBuilder {
             ctx: ctx,
             identity: None,
+            options: Vec::new(),
         }
     }
 
     /// Give the socket a custom identity
     pub fn identity(self, identity: &'a [u8]) -> Self {
         SocketBuilder {
-            ctx: self.ctx,
             identity: Some(identity),
+            ..self
         }
     }
 
+    /// Set the socket's send high-water mark
+    pub fn sndhwm(mut self, hwm: i32) -> Self {
+        self.options.push(SockOpt::Sndhwm(hwm));
+        self
+    }
+
+    /// Set the socket's receive high-water mark
+    pub fn rcvhwm(mut self, hwm: i32) -> Self {
+        self.options.push(SockOpt::Rcvhwm(hwm));
+        self
+    }
+
+    /// Set how long the socket lingers on close, waiting for unsent messages to flush
+    pub fn linger(mut self, linger: i32) -> Self {
+        self.options.push(SockOpt::Linger(linger));
+        self
+    }
+
+    /// Set the interval the socket waits before attempting to reconnect
+    pub fn reconnect_ivl(mut self, ivl: i32) -> Self {
+        self.options.push(SockOpt::ReconnectIvl(ivl));
+        self
+    }
+
+    /// Enable or disable TCP keepalive probing on the socket
+    pub fn tcp_keepalive(mut self, keepalive: i32) -> Self {
+        self.options.push(SockOpt::TcpKeepalive(keepalive));
+        self
+    }
+
+    /// Set how long, in milliseconds, `recv` blocks before giving up. `-1` (the zmq default)
+    /// waits forever.
+    pub fn rcvtimeo(mut self, timeout: i32) -> Self {
+        self.options.push(SockOpt::Rcvtimeo(timeout));
+        self
+    }
+
+    /// Set how long, in milliseconds, `send` blocks before giving up. `-1` (the zmq default)
+    /// waits forever.
+    pub fn sndtimeo(mut self, timeout: i32) -> Self {
+        self.options.push(SockOpt::Sndtimeo(timeout));
+        self
+    }
+
+    /// Apply an arbitrary `zmq::Socket` setter that doesn't have a dedicated builder method.
+    ///
+    /// Runs in the same order as the other option setters, after `ctx.socket(kind)` and before
+    /// `bind`/`connect`. Also the escape hatch for checking that one of the other setters here
+    /// took effect, via the matching `zmq::Socket::get_*` getter, against a real socket.
+    pub fn option<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&zmq::Socket) -> zmq::Result<()> + 'static,
+    {
+        self.options.push(SockOpt::Raw(Rc::new(f)));
+        self
+    }
+
+    /// Configure this socket as a CURVE server, authenticating clients against `secret_key`
+    pub fn curve_server(mut self, secret_key: &'a [u8]) -> Self {
+        self.options.push(SockOpt::CurveServer(secret_key));
+        self
+    }
+
+    /// Configure this socket as a CURVE client, authenticating itself to `server_key` with its
+    /// own `public_key`/`secret_key` keypair
+    pub fn curve_client(mut self, server_key: &'a [u8], public_key: &'a [u8], secret_key: &'a [u8]) -> Self {
+        self.options.push(SockOpt::CurveClient {
+            server_key,
+            public_key,
+            secret_key,
+        });
+        self
+    }
+
+    /// Configure this socket as a PLAIN server, authenticating clients via ZAP
+    pub fn plain_server(mut self) -> Self {
+        self.options.push(SockOpt::PlainServer);
+        self
+    }
+
+    /// Configure this socket as a PLAIN client, authenticating with `username`/`password`
+    pub fn plain_client(mut self, username: &'a str, password: &'a str) -> Self {
+        self.options.push(SockOpt::PlainClient { username, password });
+        self
+    }
+
+    /// Configure ZMTP heartbeating: ping the peer every `ivl` milliseconds, consider it dead if a
+    /// ping goes unanswered for `timeout` milliseconds, and tell the peer to give up on us after
+    /// `ttl` milliseconds of silence.
+    pub fn heartbeat(mut self, ivl: i32, timeout: i32, ttl: i32) -> Self {
+        self.options.push(SockOpt::Heartbeat { ivl, timeout, ttl });
+        self
+    }
+
     /// Bind the socket to an address
     ///
     /// Since this is just part of the builder, and the socket doesn't exist yet, we store the
@@ -84,6 +248,7 @@ impl<'a> SocketBuilder<'a> {
             bind: bind,
             connect: Vec::new(),
             identity: self.identity,
+            options: self.options,
         }
     }
 
@@ -100,6 +265,7 @@ impl<'a> SocketBuilder<'a> {
             bind: Vec::new(),
             connect: connect,
             identity: self.identity,
+            options: self.options,
         }
     }
 
@@ -112,6 +278,7 @@ impl<'a> SocketBuilder<'a> {
             addr: addr,
             bind: bind,
             identity: self.identity,
+            options: self.options,
         }
     }
 }
@@ -121,10 +288,11 @@ impl<'a> SocketBuilder<'a> {
 /// This contains all the information required to contstruct a valid socket, except in the case of
 /// SUB, which needs an additional `filter` parameter.
 pub struct SockConfig<'a> {
-    pub ctx: Rc<zmq::Context>,
+    pub ctx: Arc<zmq::Context>,
     pub bind: Vec<&'a str>,
     pub connect: Vec<&'a str>,
     pub identity: Option<&'a [u8]>,
+    pub(crate) options: Vec<SockOpt<'a>>,
 }
 
 impl<'a> SockConfig<'a> {
@@ -144,6 +312,100 @@ impl<'a> SockConfig<'a> {
         self
     }
 
+    /// Set the socket's send high-water mark
+    pub fn sndhwm(mut self, hwm: i32) -> Self {
+        self.options.push(SockOpt::Sndhwm(hwm));
+        self
+    }
+
+    /// Set the socket's receive high-water mark
+    pub fn rcvhwm(mut self, hwm: i32) -> Self {
+        self.options.push(SockOpt::Rcvhwm(hwm));
+        self
+    }
+
+    /// Set how long the socket lingers on close, waiting for unsent messages to flush
+    pub fn linger(mut self, linger: i32) -> Self {
+        self.options.push(SockOpt::Linger(linger));
+        self
+    }
+
+    /// Set the interval the socket waits before attempting to reconnect
+    pub fn reconnect_ivl(mut self, ivl: i32) -> Self {
+        self.options.push(SockOpt::ReconnectIvl(ivl));
+        self
+    }
+
+    /// Enable or disable TCP keepalive probing on the socket
+    pub fn tcp_keepalive(mut self, keepalive: i32) -> Self {
+        self.options.push(SockOpt::TcpKeepalive(keepalive));
+        self
+    }
+
+    /// Set how long, in milliseconds, `recv` blocks before giving up. `-1` (the zmq default)
+    /// waits forever.
+    pub fn rcvtimeo(mut self, timeout: i32) -> Self {
+        self.options.push(SockOpt::Rcvtimeo(timeout));
+        self
+    }
+
+    /// Set how long, in milliseconds, `send` blocks before giving up. `-1` (the zmq default)
+    /// waits forever.
+    pub fn sndtimeo(mut self, timeout: i32) -> Self {
+        self.options.push(SockOpt::Sndtimeo(timeout));
+        self
+    }
+
+    /// Apply an arbitrary `zmq::Socket` setter that doesn't have a dedicated builder method.
+    ///
+    /// Runs in the same order as the other option setters, after `ctx.socket(kind)` and before
+    /// `bind`/`connect`. Also the escape hatch for checking that one of the other setters here
+    /// took effect, via the matching `zmq::Socket::get_*` getter, against a real socket.
+    pub fn option<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&zmq::Socket) -> zmq::Result<()> + 'static,
+    {
+        self.options.push(SockOpt::Raw(Rc::new(f)));
+        self
+    }
+
+    /// Configure this socket as a CURVE server, authenticating clients against `secret_key`
+    pub fn curve_server(mut self, secret_key: &'a [u8]) -> Self {
+        self.options.push(SockOpt::CurveServer(secret_key));
+        self
+    }
+
+    /// Configure this socket as a CURVE client, authenticating itself to `server_key` with its
+    /// own `public_key`/`secret_key` keypair
+    pub fn curve_client(mut self, server_key: &'a [u8], public_key: &'a [u8], secret_key: &'a [u8]) -> Self {
+        self.options.push(SockOpt::CurveClient {
+            server_key,
+            public_key,
+            secret_key,
+        });
+        self
+    }
+
+    /// Configure this socket as a PLAIN server, authenticating clients via ZAP
+    pub fn plain_server(mut self) -> Self {
+        self.options.push(SockOpt::PlainServer);
+        self
+    }
+
+    /// Configure this socket as a PLAIN client, authenticating with `username`/`password`
+    pub fn plain_client(mut self, username: &'a str, password: &'a str) -> Self {
+        self.options.push(SockOpt::PlainClient { username, password });
+        self
+    }
+
+    /// Configure ZMTP heartbeating: ping the peer every `ivl` milliseconds, consider it dead if a
+    /// ping goes unanswered for `timeout` milliseconds, and tell the peer to give up on us after
+    /// `ttl` milliseconds of silence.
+    pub fn heartbeat(mut self, ivl: i32, timeout: i32, ttl: i32) -> Self {
+        self.options.push(SockOpt::Heartbeat { ivl, timeout, ttl });
+        self
+    }
+
     /// Finalize the `SockConfig` into a `Socket` if the creation is successful, or into an Error
     /// if something went wrong.
     ///
@@ -160,12 +422,14 @@ impl<'a> SockConfig<'a> {
             bind,
             connect,
             identity,
+            options,
         } = self;
 
         let sock = ctx.socket(kind)?;
         if let Some(identity) = identity {
             sock.set_identity(identity)?;
         }
+        apply_options(&sock, &options)?;
         let sock = bind_all(sock, &bind)?;
         let sock = connect_all(sock, &connect)?;
 
@@ -178,13 +442,28 @@ impl<'a> SockConfig<'a> {
 
     /// Continue the building process into a SubConfig, for the SUB socket type which requires
     /// setting a subscription filter.
+    ///
+    /// Call `filter` again on the resulting `SubConfig` to subscribe to additional topics.
     pub fn filter(self, pattern: &'a [u8]) -> SubConfig<'a> {
         SubConfig {
             ctx: self.ctx,
             bind: self.bind,
             connect: self.connect,
             identity: self.identity,
-            filter: pattern,
+            options: self.options,
+            filters: vec![pattern],
+        }
+    }
+
+    /// Like `filter`, but subscribe to every topic in `patterns` at once.
+    pub fn filters(self, patterns: &[&'a [u8]]) -> SubConfig<'a> {
+        SubConfig {
+            ctx: self.ctx,
+            bind: self.bind,
+            connect: self.connect,
+            identity: self.identity,
+            options: self.options,
+            filters: patterns.to_vec(),
         }
     }
 }
@@ -193,14 +472,27 @@ impl<'a> SockConfig<'a> {
 ///
 /// This contains all the information required to contstruct a valid SUB socket
 pub struct SubConfig<'a> {
-    pub ctx: Rc<zmq::Context>,
+    pub ctx: Arc<zmq::Context>,
     pub bind: Vec<&'a str>,
     pub connect: Vec<&'a str>,
-    pub filter: &'a [u8],
+    pub filters: Vec<&'a [u8]>,
     pub identity: Option<&'a [u8]>,
+    pub(crate) options: Vec<SockOpt<'a>>,
 }
 
 impl<'a> SubConfig<'a> {
+    /// Subscribe to an additional topic prefix.
+    pub fn filter(mut self, pattern: &'a [u8]) -> Self {
+        self.filters.push(pattern);
+        self
+    }
+
+    /// Subscribe to every topic prefix in `patterns` at once.
+    pub fn filters(mut self, patterns: &[&'a [u8]]) -> Self {
+        self.filters.extend_from_slice(patterns);
+        self
+    }
+
     /// Finalize the `SubConfig` into a `Socket` if the creation is successful, or into an Error
     /// if something went wrong.
     ///
@@ -217,17 +509,21 @@ impl<'a> SubConfig<'a> {
             ctx,
             bind,
             connect,
-            filter,
+            filters,
             identity,
+            options,
         } = self;
 
         let sock = ctx.socket(zmq::SUB)?;
         if let Some(identity) = identity {
             sock.set_identity(identity)?;
         }
+        apply_options(&sock, &options)?;
         let sock = bind_all(sock, &bind)?;
         let sock = connect_all(sock, &connect)?;
-        sock.set_subscribe(filter)?;
+        for filter in &filters {
+            sock.set_subscribe(filter)?;
+        }
 
         let fd = sock.get_fd()?;
 
@@ -241,10 +537,11 @@ impl<'a> SubConfig<'a> {
 ///
 /// This contains all the information required to contstruct a valid PAIR socket
 pub struct PairConfig<'a> {
-    ctx: Rc<zmq::Context>,
+    ctx: Arc<zmq::Context>,
     addr: &'a str,
     bind: bool,
     identity: Option<&'a [u8]>,
+    options: Vec<SockOpt<'a>>,
 }
 
 impl<'a> PairConfig<'a> {
@@ -260,12 +557,14 @@ impl<'a> PairConfig<'a> {
             addr,
             bind,
             identity,
+            options,
         } = self;
 
         let sock = ctx.socket(zmq::PAIR)?;
         if let Some(identity) = identity {
             sock.set_identity(identity)?;
         }
+        apply_options(&sock, &options)?;
         if bind {
             sock.bind(addr)?;
         } else {
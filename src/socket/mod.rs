@@ -23,6 +23,7 @@ pub mod config;
 pub mod types;
 
 use std::sync::Arc;
+use std::time::Duration;
 
 use zmq;
 use tokio::reactor::PollEvented2;
@@ -54,6 +55,12 @@ impl Socket {
         (self.sock, self.file)
     }
 
+    /// Borrow the underlying `zmq::Socket`, e.g. to read or change a `setsockopt` at runtime
+    /// without consuming the wrapper.
+    pub fn as_raw_socket(&self) -> &zmq::Socket {
+        &self.sock
+    }
+
     /// Create a new socket from a given Sock and File
     ///
     /// This assumes that `sock` is already configured properly. Please don't call this directly
@@ -67,6 +74,12 @@ impl Socket {
         MultipartSink::new(self.sock, self.file)
     }
 
+    /// Retrieve a Sink that consumes Multiparts, buffering up to `capacity` of them in-memory
+    /// before applying backpressure to the upstream producer.
+    pub fn sink_with_capacity(self, capacity: usize) -> MultipartSink {
+        MultipartSink::with_capacity(self.sock, self.file, capacity)
+    }
+
     /// Retrieve a Stream that produces Multiparts, getting them from the socket
     pub fn stream(self) -> MultipartStream {
         MultipartStream::new(self.sock, self.file)
@@ -78,13 +91,59 @@ impl Socket {
         MultipartSinkStream::new(self.sock, self.file)
     }
 
+    /// Retrieve a `MultipartSinkStream` whose sink half buffers up to `capacity` outstanding
+    /// Multiparts in-memory before applying backpressure to the upstream producer.
+    pub fn sink_stream_with_capacity(self, capacity: usize) -> MultipartSinkStream {
+        MultipartSinkStream::with_capacity(self.sock, self.file, capacity)
+    }
+
     /// Retrieve a Future that consumes a multipart, sending it to the socket
-    pub fn send(self, multipart: Multipart) -> MultipartRequest {
+    pub fn send<T>(self, multipart: Multipart) -> MultipartRequest<T>
+    where
+        T: From<(zmq::Socket, PollEvented2<File<ZmqFile>>)>,
+    {
         MultipartRequest::new(self.sock, self.file, multipart)
     }
 
+    /// Retrieve a Future that consumes frames convertible into `zmq::Message` (e.g. `&[u8]`,
+    /// `Vec<u8>`), sending them to the socket without materializing a `zmq::Message` for any
+    /// frame until it's actually sent.
+    pub fn send_frames<T, S, I>(self, frames: I) -> MultipartRequest<T, S>
+    where
+        T: From<(zmq::Socket, PollEvented2<File<ZmqFile>>)>,
+        S: Into<zmq::Message>,
+        I: IntoIterator<Item = S>,
+    {
+        MultipartRequest::from_frames(self.sock, self.file, frames)
+    }
+
+    /// Like `send`, but fails with `Error::Timeout` if the send doesn't finish within `duration`.
+    pub fn send_timeout<T>(self, multipart: Multipart, duration: Duration) -> MultipartRequest<T>
+    where
+        T: From<(zmq::Socket, PollEvented2<File<ZmqFile>>)>,
+    {
+        MultipartRequest::with_timeout(self.sock, self.file, multipart, duration)
+    }
+
     /// Retrieve a Future that produces a multipart, getting it fromthe socket
-    pub fn recv(self) -> MultipartResponse {
+    pub fn recv<T>(self) -> MultipartResponse<T>
+    where
+        T: From<(zmq::Socket, PollEvented2<File<ZmqFile>>)>,
+    {
         MultipartResponse::new(self.sock, self.file)
     }
+
+    /// Like `recv`, but fails with `Error::Timeout` if no frame arrives within `duration`.
+    pub fn recv_timeout<T>(self, duration: Duration) -> MultipartResponse<T>
+    where
+        T: From<(zmq::Socket, PollEvented2<File<ZmqFile>>)>,
+    {
+        MultipartResponse::with_timeout(self.sock, self.file, duration)
+    }
+}
+
+impl From<(zmq::Socket, PollEvented2<File<ZmqFile>>)> for Socket {
+    fn from(tup: (zmq::Socket, PollEvented2<File<ZmqFile>>)) -> Self {
+        Socket::from_sock_and_file(tup.0, tup.1)
+    }
 }
@@ -20,13 +20,21 @@
 //! This module defines all the socket wrapper types that can be used with Tokio.
 
 use std::convert::TryFrom;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
 use tokio_reactor::PollEvented;
 use tokio_file_unix::File;
 use zmq;
 
+use futures::Stream;
+
+use async::{MultipartRequest, MultipartResponse, MultipartStream};
 use error::Error;
 use file::ZmqFile;
+use message::Multipart;
+use prelude::{RoutingEnvelope, SubscriptionEvent};
 use socket::config::{PairConfig, SockConfig, SubConfig};
 use socket::Socket;
 
@@ -117,6 +125,11 @@ pub struct Req {
 /// The ROUTER `SocketType` wrapper type
 ///
 /// Router implements `StreamSocket` and `SinkSocket`, and has an associated controlled variant.
+///
+/// ZeroMQ prepends the identity frame of the originating peer to every `Multipart` a ROUTER
+/// socket receives, and expects that same identity frame to lead the `Multipart` you send back.
+/// This wrapper does not strip or otherwise touch that frame, so routing logic can pop it from
+/// the front of the `Multipart` on recv and push it back on send.
 #[derive(SocketWrapper)]
 #[stream]
 #[sink]
@@ -124,6 +137,43 @@ pub struct Router {
     inner: Socket,
 }
 
+impl Router {
+    /// Receive a single multipart message from the socket, with the ROUTER-prepended identity
+    /// frame(s) already split out into a `RoutingEnvelope`.
+    pub fn recv_envelope(self) -> RouterResponse {
+        RouterResponse {
+            inner: self.inner.recv(),
+        }
+    }
+
+    /// Send a `RoutingEnvelope` to the socket, reassembling it into the
+    /// `[identity...][empty][payload...]` framing ZeroMQ expects back from a ROUTER socket.
+    pub fn send_envelope(self, envelope: RoutingEnvelope) -> MultipartRequest<Router> {
+        self.inner.send(envelope.into_multipart())
+    }
+}
+
+/// The `Future` returned by `Router::recv_envelope`.
+pub struct RouterResponse {
+    inner: MultipartResponse<Router>,
+}
+
+impl Future for RouterResponse {
+    type Output = Result<(RoutingEnvelope, Router), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        match Pin::new(&mut this.inner).poll(cx) {
+            Poll::Ready(Ok((multipart, router))) => {
+                Poll::Ready(Ok((RoutingEnvelope::from_multipart(multipart), router)))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
 /* -------------------------------------------------------------------------- */
 
 /// The SUB `SocketType` wrapper type
@@ -136,6 +186,88 @@ pub struct Sub {
     inner: Socket,
 }
 
+impl Sub {
+    /// Subscribe to an additional topic prefix without rebuilding the socket.
+    pub fn subscribe(&self, topic: &[u8]) -> Result<(), Error> {
+        self.inner.as_raw_socket().set_subscribe(topic)?;
+        Ok(())
+    }
+
+    /// Drop a previously-subscribed topic prefix without rebuilding the socket.
+    pub fn unsubscribe(&self, topic: &[u8]) -> Result<(), Error> {
+        self.inner.as_raw_socket().set_unsubscribe(topic)?;
+        Ok(())
+    }
+
+    /// Receive the socket's multipart stream, applying `Subscription` commands pulled from
+    /// `commands` as they arrive.
+    ///
+    /// This lets a long-lived subscriber change which topics it receives without tearing down and
+    /// rebuilding the socket, which `subscribe`/`unsubscribe` already allow, but now driven by a
+    /// control stream instead of requiring direct access to the still-live `Sub` handle.
+    pub fn stream_with_subscriptions<S>(self, commands: S) -> SubscriptionControlledStream<S>
+    where
+        S: Stream<Item = Result<Subscription, Error>> + Unpin,
+    {
+        SubscriptionControlledStream {
+            stream: self.inner.stream(),
+            commands,
+        }
+    }
+}
+
+/// A command to dynamically change a `Sub` socket's active topic filters at runtime, for use with
+/// `Sub::stream_with_subscriptions`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Subscription {
+    /// Start receiving messages whose topic begins with this prefix.
+    Subscribe(Vec<u8>),
+    /// Stop receiving messages whose topic begins with this prefix.
+    Unsubscribe(Vec<u8>),
+}
+
+/// The `Stream` returned by `Sub::stream_with_subscriptions`.
+pub struct SubscriptionControlledStream<S> {
+    stream: MultipartStream,
+    commands: S,
+}
+
+impl<S> Stream for SubscriptionControlledStream<S>
+where
+    S: Stream<Item = Result<Subscription, Error>> + Unpin,
+{
+    type Item = Result<Multipart, Error>;
+
+    /// Drain every `Subscription` command that's already arrived, applying each to the
+    /// underlying socket, then poll for the next incoming multipart.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            match Pin::new(&mut this.commands).poll_next(cx) {
+                Poll::Ready(Some(Ok(command))) => {
+                    let result = match command {
+                        Subscription::Subscribe(topic) => {
+                            this.stream.as_raw_socket().set_subscribe(&topic)
+                        }
+                        Subscription::Unsubscribe(topic) => {
+                            this.stream.as_raw_socket().set_unsubscribe(&topic)
+                        }
+                    };
+
+                    if let Err(e) = result {
+                        return Poll::Ready(Some(Err(e.into())));
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) | Poll::Pending => break,
+            }
+        }
+
+        Pin::new(&mut this.stream).poll_next(cx)
+    }
+}
+
 /* -------------------------------------------------------------------------- */
 
 /// The XPUB `SocketType` wrapper type
@@ -148,6 +280,56 @@ pub struct Xpub {
     inner: Socket,
 }
 
+impl Xpub {
+    /// Turn on `ZMQ_XPUB_VERBOSE`, so `subscription_stream` also surfaces duplicate
+    /// subscriptions instead of ZeroMQ's default of only reporting a topic's first subscriber.
+    pub fn set_verbose(&self, verbose: bool) -> Result<(), Error> {
+        self.inner.as_raw_socket().set_xpub_verbose(verbose)?;
+        Ok(())
+    }
+
+    /// Receive the stream of subscribe/unsubscribe notifications this XPUB socket's peers send
+    /// whenever they (un)subscribe to a topic, instead of the raw control frames `stream()` would
+    /// yield.
+    pub fn subscription_stream(self) -> SubscriptionStream {
+        SubscriptionStream {
+            inner: self.inner.stream(),
+        }
+    }
+}
+
+/// The `Stream` returned by `Xpub::subscription_stream`.
+pub struct SubscriptionStream {
+    inner: MultipartStream,
+}
+
+impl Stream for SubscriptionStream {
+    type Item = Result<SubscriptionEvent, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        match Pin::new(&mut this.inner).poll_next(cx) {
+            Poll::Ready(Some(Ok(multipart))) => {
+                let event = multipart.get(0).and_then(SubscriptionEvent::from_message);
+
+                match event {
+                    Some(event) => Poll::Ready(Some(Ok(event))),
+                    // Not a well-formed XPUB control frame; skip it rather than stopping the
+                    // whole stream over one malformed notification.
+                    None => {
+                        cx.waker().wake_by_ref();
+                        Poll::Pending
+                    }
+                }
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
 /* -------------------------------------------------------------------------- */
 
 /// The XSUB `SocketType` wrapper type
@@ -159,3 +341,19 @@ pub struct Xpub {
 pub struct Xsub {
     inner: Socket,
 }
+
+/* -------------------------------------------------------------------------- */
+
+/// The STREAM `SocketType` wrapper type
+///
+/// Stream implements `StreamSocket` and `SinkSocket`, and has an associated controlled variant.
+///
+/// Unlike the other wrapper types, a STREAM socket talks to non-ZMTP TCP peers: every `Multipart`
+/// it sends or receives is framed as `[identity][raw bytes]` rather than a ZMTP envelope, where
+/// the identity frame addresses one specific TCP connection.
+#[derive(SocketWrapper)]
+#[stream]
+#[sink]
+pub struct Stream {
+    inner: Socket,
+}
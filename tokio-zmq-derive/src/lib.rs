@@ -20,8 +20,8 @@ pub fn socket_derive(input: TokenStream) -> TokenStream {
     let name = input.ident;
 
     let from_parts = quote! {
-        impl From<(zmq::Socket, PollEvented<File<ZmqFile>>)> for #name {
-            fn from(tup: (zmq::Socket, PollEvented<File<ZmqFile>>)) -> Self {
+        impl From<(zmq::Socket, PollEvented2<File<ZmqFile>>)> for #name {
+            fn from(tup: (zmq::Socket, PollEvented2<File<ZmqFile>>)) -> Self {
                 #name {
                     inner: tup.into()
                 }
@@ -37,9 +37,17 @@ pub fn socket_derive(input: TokenStream) -> TokenStream {
         }
     };
 
-    let try_from = {
-        let socket_type = Ident::from(format!("{}", name).to_uppercase().as_ref());
+    let socket_type = Ident::from(format!("{}", name).to_uppercase().as_ref());
+
+    let zmq_socket = quote! {
+        impl ::prelude::ZmqSocket for #name {
+            fn socket_kind(&self) -> zmq::SocketType {
+                zmq::#socket_type
+            }
+        }
+    };
 
+    let try_from = {
         let try_from_attr = input.attrs.iter().find(|attr| {
             attr.path
                 .segments
@@ -101,6 +109,7 @@ pub fn socket_derive(input: TokenStream) -> TokenStream {
     let full = quote! {
         #from_parts
         #as_socket
+        #zmq_socket
         #stream
         #sink
         #try_from